@@ -0,0 +1,353 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use pulldown_cmark_portabletext::portabletext::batch::{self, BatchOptions};
+use pulldown_cmark_portabletext::portabletext::{self, BlockNode, Diagnostic};
+
+const USAGE: &str = "Usage: md2pt [OPTIONS] [FILE...]
+       md2pt [OPTIONS] --dir DIR --out-dir OUT [--glob PATTERN]
+
+Converts Markdown to Portable Text JSON. Reads from stdin when no FILE
+(or `-`) is given. With --dir every file matching PATTERN (default
+`**/*.md`) below DIR is converted into the same relative path below OUT.
+
+Options:
+  --tables             Enable tables (ENABLE_TABLES)
+  --footnotes          Enable footnotes (ENABLE_FOOTNOTES)
+  --strikethrough      Enable strikethrough (ENABLE_STRIKETHROUGH)
+  --tasklists          Enable task lists (ENABLE_TASKLISTS)
+  --smart-punctuation  Enable smart punctuation (ENABLE_SMART_PUNCTUATION)
+  --all                Enable all of the above
+  --math               Convert $inline$ and $$display$$ math to math objects
+  --compact            Write compact JSON instead of pretty printed JSON
+  -o, --out-dir DIR    Write one <name>.json per input file into DIR
+  --dir DIR            Convert a directory tree (requires --out-dir)
+  --glob PATTERN       Files to convert in --dir mode (default **/*.md)
+  -h, --help           Print this help";
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    parser_options: pulldown_cmark::Options,
+    math: bool,
+    compact: bool,
+    out_dir: Option<PathBuf>,
+    dir: Option<PathBuf>,
+    glob: Option<String>,
+    inputs: Vec<PathBuf>,
+}
+
+enum Command {
+    Convert(Args),
+    Help,
+}
+
+fn parse_args<I>(args: I) -> Result<Command, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut parsed = Args {
+        parser_options: pulldown_cmark::Options::empty(),
+        math: false,
+        compact: false,
+        out_dir: None,
+        dir: None,
+        glob: None,
+        inputs: vec![],
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tables" => parsed
+                .parser_options
+                .insert(pulldown_cmark::Options::ENABLE_TABLES),
+            "--footnotes" => parsed
+                .parser_options
+                .insert(pulldown_cmark::Options::ENABLE_FOOTNOTES),
+            "--strikethrough" => parsed
+                .parser_options
+                .insert(pulldown_cmark::Options::ENABLE_STRIKETHROUGH),
+            "--tasklists" => parsed
+                .parser_options
+                .insert(pulldown_cmark::Options::ENABLE_TASKLISTS),
+            "--smart-punctuation" => parsed
+                .parser_options
+                .insert(pulldown_cmark::Options::ENABLE_SMART_PUNCTUATION),
+            "--all" => parsed.parser_options = pulldown_cmark::Options::all(),
+            "--math" => parsed.math = true,
+            "--compact" => parsed.compact = true,
+            "-o" | "--out-dir" => match args.next() {
+                Some(dir) => parsed.out_dir = Some(PathBuf::from(dir)),
+                None => return Err(format!("{} expects a directory", arg)),
+            },
+            "--dir" => match args.next() {
+                Some(dir) => parsed.dir = Some(PathBuf::from(dir)),
+                None => return Err(format!("{} expects a directory", arg)),
+            },
+            "--glob" => match args.next() {
+                Some(pattern) => parsed.glob = Some(pattern),
+                None => return Err(format!("{} expects a pattern", arg)),
+            },
+            "-h" | "--help" => return Ok(Command::Help),
+            "-" => parsed.inputs.push(PathBuf::from(arg)),
+            flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+            _ => parsed.inputs.push(PathBuf::from(arg)),
+        }
+    }
+
+    if parsed.dir.is_some() {
+        if parsed.out_dir.is_none() {
+            return Err("--dir requires --out-dir".to_owned());
+        }
+        if !parsed.inputs.is_empty() {
+            return Err("--dir cannot be combined with input files".to_owned());
+        }
+        return Ok(Command::Convert(parsed));
+    }
+    if parsed.glob.is_some() {
+        return Err("--glob can only be used with --dir".to_owned());
+    }
+    if parsed.out_dir.is_some() && parsed.inputs.iter().any(|p| is_stdin(p)) {
+        return Err("--out-dir cannot be combined with reading from stdin".to_owned());
+    }
+    if parsed.out_dir.is_some() && parsed.inputs.is_empty() {
+        return Err("--out-dir requires at least one input file".to_owned());
+    }
+
+    Ok(Command::Convert(parsed))
+}
+
+fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+fn read_input(path: &Path) -> io::Result<String> {
+    if is_stdin(path) {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input)?;
+        Ok(input)
+    } else {
+        fs::read_to_string(path)
+    }
+}
+
+fn convert(
+    markdown_input: &str,
+    parser_options: pulldown_cmark::Options,
+    options: portabletext::Options,
+) -> io::Result<(Vec<BlockNode>, Vec<Diagnostic>)> {
+    let mut portabletext_output = vec![];
+    let diagnostics = portabletext::try_push_markdown(
+        &mut portabletext_output,
+        markdown_input,
+        parser_options,
+        options,
+    )?;
+    Ok((portabletext_output, diagnostics))
+}
+
+fn options(args: &Args) -> portabletext::Options<'static> {
+    portabletext::Options {
+        math: args.math,
+        ..portabletext::Options::default()
+    }
+}
+
+fn to_json(blocks: &[BlockNode], compact: bool) -> io::Result<String> {
+    let json = if compact {
+        serde_json::to_string(blocks)
+    } else {
+        serde_json::to_string_pretty(blocks)
+    };
+    json.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn output_path(out_dir: &Path, input: &Path) -> PathBuf {
+    let stem = input.file_stem().unwrap_or(input.as_os_str());
+    let mut name = stem.to_owned();
+    name.push(".json");
+    out_dir.join(name)
+}
+
+/// The output path of each input, refusing inputs that would overwrite each
+/// other's output, like `a/post.md` and `b/post.md`.
+fn output_paths(out_dir: &Path, inputs: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut paths: Vec<PathBuf> = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let path = output_path(out_dir, input);
+        if let Some(other) = paths.iter().position(|other| *other == path) {
+            return Err(format!(
+                "{} and {} would both be written to {}",
+                inputs[other].display(),
+                input.display(),
+                path.display()
+            ));
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+fn run_dir(input_dir: &Path, out_dir: &Path, args: &Args) -> io::Result<bool> {
+    let mut batch_options = BatchOptions {
+        parser_options: args.parser_options,
+        options: options(args),
+        compact: args.compact,
+        ..BatchOptions::default()
+    };
+    if let Some(pattern) = &args.glob {
+        batch_options.pattern = pattern.to_owned();
+    }
+
+    let report = batch::convert_dir(input_dir, out_dir, &batch_options)?;
+    for diagnostic in &report.diagnostics {
+        eprintln!(
+            "md2pt: {}: {}",
+            diagnostic.path.display(),
+            diagnostic.diagnostic
+        );
+    }
+    for failure in &report.failures {
+        eprintln!("md2pt: {}: {}", failure.path.display(), failure.error);
+    }
+    eprintln!(
+        "md2pt: converted {} file(s), {} failure(s)",
+        report.converted.len(),
+        report.failures.len()
+    );
+    Ok(report.is_success())
+}
+
+fn run(args: Args) -> io::Result<bool> {
+    if let (Some(input_dir), Some(out_dir)) = (&args.dir, &args.out_dir) {
+        return run_dir(input_dir, out_dir, &args);
+    }
+
+    let options = options(&args);
+    let inputs = if args.inputs.is_empty() {
+        vec![PathBuf::from("-")]
+    } else {
+        args.inputs
+    };
+
+    let output_paths = match &args.out_dir {
+        Some(out_dir) => {
+            let paths = output_paths(out_dir, &inputs)
+                .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e))?;
+            fs::create_dir_all(out_dir)?;
+            Some(paths)
+        }
+        None => None,
+    };
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for (index, input) in inputs.iter().enumerate() {
+        let markdown_input = read_input(input)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", input.display(), e)))?;
+        let (blocks, diagnostics) = convert(&markdown_input, args.parser_options, options.clone())
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", input.display(), e)))?;
+        for diagnostic in diagnostics {
+            eprintln!("md2pt: {}: {}", input.display(), diagnostic);
+        }
+        let json = to_json(&blocks, args.compact)?;
+
+        match &output_paths {
+            Some(paths) => fs::write(&paths[index], json + "\n")?,
+            None => writeln!(stdout, "{}", json)?,
+        }
+    }
+    Ok(true)
+}
+
+fn main() {
+    match parse_args(env::args().skip(1)) {
+        Ok(Command::Help) => println!("{}", USAGE),
+        Ok(Command::Convert(args)) => match run(args) {
+            Ok(true) => {}
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                eprintln!("md2pt: {}", e);
+                process::exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("md2pt: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parses_extension_flags_and_inputs() {
+        let parsed = match args(&["--tables", "--strikethrough", "--math", "--compact", "a.md"]) {
+            Ok(Command::Convert(parsed)) => parsed,
+            _ => panic!(),
+        };
+
+        let mut expected_options = pulldown_cmark::Options::empty();
+        expected_options.insert(pulldown_cmark::Options::ENABLE_TABLES);
+        expected_options.insert(pulldown_cmark::Options::ENABLE_STRIKETHROUGH);
+
+        assert_eq!(expected_options, parsed.parser_options);
+        assert!(parsed.math);
+        assert!(parsed.compact);
+        assert_eq!(vec![PathBuf::from("a.md")], parsed.inputs);
+    }
+
+    #[test]
+    fn rejects_out_dir_for_stdin() {
+        assert!(args(&["--out-dir", "out"]).is_err());
+        assert!(args(&["-o", "out", "-"]).is_err());
+        assert!(args(&["--nope"]).is_err());
+    }
+
+    #[test]
+    fn dir_mode_requires_out_dir() {
+        assert!(args(&["--dir", "content"]).is_err());
+        assert!(args(&["--dir", "content", "-o", "out", "a.md"]).is_err());
+        assert!(args(&["--glob", "*.md", "a.md"]).is_err());
+
+        let parsed = match args(&["--dir", "content", "-o", "out", "--glob", "*.md"]) {
+            Ok(Command::Convert(parsed)) => parsed,
+            _ => panic!(),
+        };
+        assert_eq!(Some(PathBuf::from("content")), parsed.dir);
+        assert_eq!(Some("*.md".to_owned()), parsed.glob);
+    }
+
+    #[test]
+    fn output_path_mirrors_file_stem() {
+        assert_eq!(
+            PathBuf::from("out/post.json"),
+            output_path(Path::new("out"), Path::new("content/post.md"))
+        );
+        assert_eq!(
+            PathBuf::from("out/post.v2.json"),
+            output_path(Path::new("out"), Path::new("post.v2.md"))
+        );
+    }
+
+    #[test]
+    fn rejects_inputs_with_the_same_output() {
+        let inputs = vec![PathBuf::from("a/post.md"), PathBuf::from("b/post.md")];
+        let error = output_paths(Path::new("out"), &inputs).unwrap_err();
+
+        assert_eq!(
+            "a/post.md and b/post.md would both be written to out/post.json",
+            error
+        );
+        assert!(output_paths(Path::new("out"), &inputs[..1]).is_ok());
+    }
+}
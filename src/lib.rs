@@ -1,13 +1,44 @@
 pub mod portabletext {
-    use core::panic;
+    pub mod annotations;
+    #[cfg(feature = "serde_serialization")]
+    pub mod batch;
+    pub mod diff;
+    pub mod directives;
+    #[cfg(feature = "embeds")]
+    pub mod embeds;
+    pub mod fences;
+    pub mod image_size;
+    pub mod links;
+    pub mod local;
+    pub mod math;
+    pub mod normalize;
+    #[cfg(feature = "placeholders")]
+    pub mod placeholder;
+    pub mod plain_text;
+    pub mod resolvers;
+    pub mod responsive;
+    #[cfg(feature = "serde_serialization")]
+    pub mod sanity;
+    pub mod sanity_assets;
+    pub mod url;
+    pub mod value;
+
+    use std::collections::{BTreeMap, HashMap, VecDeque};
+    use std::error::Error;
+    use std::fmt;
     use std::io;
+    use std::mem;
+    use std::ops::Range;
 
     use pulldown_cmark::Event::*;
-    use pulldown_cmark::{CodeBlockKind, CowStr, Event, Tag};
+    use pulldown_cmark::{CodeBlockKind, CowStr, Event, Parser, Tag};
     use rand::distributions::Alphanumeric;
     use rand::{thread_rng, Rng};
     #[cfg(feature = "serde_serialization")]
+    use serde::ser::SerializeMap;
+    #[cfg(feature = "serde_serialization")]
     use serde::{Serialize, Serializer};
+    use value::Value;
 
     #[cfg(feature = "serde_serialization")]
     impl Serialize for Decorators {
@@ -18,11 +49,13 @@ pub mod portabletext {
             match self {
                 Decorators::LinkReference(d) => serializer.serialize_str(d),
                 Decorators::AssetReference(a) => serializer.serialize_str(a),
+                Decorators::AnnotationReference(a) => serializer.serialize_str(a),
                 Decorators::Emphasis => serializer.serialize_str("em"),
                 Decorators::Strong => serializer.serialize_str("strong"),
                 Decorators::Strike => serializer.serialize_str("strike"),
                 Decorators::Underline => serializer.serialize_str("underline"),
                 Decorators::Code => serializer.serialize_str("code"),
+                Decorators::Custom(name) => serializer.serialize_str(name),
             }
         }
     }
@@ -46,39 +79,567 @@ pub mod portabletext {
         pub width: u32,
         pub height: u32,
         pub sources: Vec<Source>,
+        #[cfg_attr(
+            feature = "serde_serialization",
+            serde(skip_serializing_if = "Option::is_none")
+        )]
+        pub placeholder: Option<Placeholder>,
+    }
+
+    /// Preview data to show while the image loads.
+    #[derive(Debug, PartialEq, Clone, Default)]
+    #[cfg_attr(feature = "serde_serialization", derive(Serialize))]
+    #[cfg_attr(feature = "serde_serialization", serde(rename_all = "camelCase"))]
+    pub struct Placeholder {
+        /// A tiny thumbnail as a `data:` URL.
+        #[cfg_attr(
+            feature = "serde_serialization",
+            serde(skip_serializing_if = "Option::is_none")
+        )]
+        pub lqip: Option<String>,
+        #[cfg_attr(
+            feature = "serde_serialization",
+            serde(skip_serializing_if = "Option::is_none")
+        )]
+        pub blurhash: Option<String>,
+        /// CSS hex color, e.g. `#a0522d`.
+        #[cfg_attr(
+            feature = "serde_serialization",
+            serde(skip_serializing_if = "Option::is_none")
+        )]
+        pub dominant_color: Option<String>,
+    }
+
+    /// Everything known about an image at the point it is resolved.
+    #[derive(Debug, Clone)]
+    pub struct ImageContext<'c> {
+        /// The image URL as written in the Markdown.
+        pub reference: &'c str,
+        pub alt: &'c str,
+        pub title: Option<&'c str>,
+        /// The block the image is placed in.
+        pub block: Option<&'c BlockNode>,
+        /// Byte range of the image in the Markdown source, only known when
+        /// converting with `try_push_portabletext_with_offsets`.
+        pub source_position: Option<Range<usize>>,
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct ResolveError {
+        pub reference: String,
+        pub message: String,
     }
 
+    impl ResolveError {
+        pub fn new(reference: &str, message: impl Into<String>) -> Self {
+            Self {
+                reference: reference.to_owned(),
+                message: message.into(),
+            }
+        }
+    }
+
+    impl fmt::Display for ResolveError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "cannot resolve {}: {}", self.reference, self.message)
+        }
+    }
+
+    impl Error for ResolveError {}
+
     pub trait AssetReferenceResolver {
-        fn resolve(&self, reference: String) -> String;
-        fn resolve_picture(&self, reference: String, alt: String) -> Picture;
+        fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError>;
+        fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError>;
+
+        /// A reference to the image in an asset store, if the resolver knows one.
+        fn resolve_asset(&self, _context: &ImageContext) -> Result<Option<Asset>, ResolveError> {
+            Ok(None)
+        }
     }
 
     struct IdentityResolver;
 
     impl AssetReferenceResolver for IdentityResolver {
-        fn resolve(&self, reference: String) -> String {
-            reference
+        fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+            Ok(context.reference.to_owned())
         }
 
-        fn resolve_picture(&self, reference: String, alt: String) -> Picture {
-            Picture {
-                src: reference,
-                alt,
+        fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+            Ok(Picture {
+                src: context.reference.to_owned(),
+                alt: context.alt.to_owned(),
                 width: 999,
                 height: 999,
                 sources: vec![],
+                placeholder: None,
+            })
+        }
+    }
+
+    /// Everything known about a link at the point it is resolved.
+    #[derive(Debug, Clone)]
+    pub struct LinkContext<'c> {
+        /// The link URL as written in the Markdown.
+        pub href: &'c str,
+        pub title: Option<&'c str>,
+        /// The block the link is placed in.
+        pub block: Option<&'c BlockNode>,
+        /// Byte range of the link in the Markdown source, only known when
+        /// converting with `try_push_portabletext_with_offsets`.
+        pub source_position: Option<Range<usize>>,
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum ResolvedLink {
+        /// A `link` annotation to this URL.
+        Href(String),
+        /// An `internalLink` annotation referencing this document id.
+        Internal(String),
+    }
+
+    /// Decides what a link in the Markdown turns into. Links that fail to
+    /// resolve are kept as written and reported as a `Diagnostic`.
+    pub trait LinkResolver {
+        fn resolve_link(&self, context: &LinkContext) -> Result<ResolvedLink, ResolveError>;
+    }
+
+    struct IdentityLinkResolver;
+
+    impl LinkResolver for IdentityLinkResolver {
+        fn resolve_link(&self, context: &LinkContext) -> Result<ResolvedLink, ResolveError> {
+            Ok(ResolvedLink::Href(context.href.to_owned()))
+        }
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum AnnotationEvent {
+        /// Starts an annotation of this `_type` with these fields.
+        Open {
+            _type: String,
+            fields: BTreeMap<String, Value>,
+        },
+        /// Ends the innermost open annotation of this `_type`.
+        Close { _type: String },
+    }
+
+    /// Turns inline HTML into custom annotations, like
+    /// `<abbr title="…">` into a glossary term. Annotations still open at the
+    /// end of a block are closed there.
+    pub trait AnnotationHandler {
+        fn inline_html(&self, html: &str) -> Option<AnnotationEvent>;
+    }
+
+    struct NoAnnotations;
+
+    impl AnnotationHandler for NoAnnotations {
+        fn inline_html(&self, _html: &str) -> Option<AnnotationEvent> {
+            None
+        }
+    }
+
+    /// How the content of a fenced block is passed to its `FenceHandler`.
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum FenceFormat {
+        /// As a `Value::String`.
+        Raw,
+        /// Parsed with `serde_json`, requires the `fence_formats` feature.
+        Json,
+        /// Parsed with `serde_yaml`, requires the `fence_formats` feature.
+        Yaml,
+    }
+
+    /// Turns fenced blocks of a language into custom blocks instead of `code`
+    /// blocks, see `Options::fence_handlers`.
+    pub trait FenceHandler {
+        fn format(&self) -> FenceFormat;
+
+        /// Builds the block from the parsed content. `info` is the whole info
+        /// string, e.g. `chart title="Sales"`. Errors are reported as a
+        /// `DiagnosticKind::InvalidFence` and the block is kept as code.
+        fn block(&self, info: &str, content: Value) -> Result<BlockNode, String>;
+    }
+
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum DirectiveKind {
+        /// `:::name` around blocks, up to a `:::` line.
+        Container,
+        /// `::name` on its own line.
+        Leaf,
+        /// `:name[label]` in text.
+        Text,
+    }
+
+    /// A remark-directive style directive, like `:::note[Title]{.wide}` or
+    /// `:abbr[HTML]{title="HyperText Markup Language"}`.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct Directive {
+        pub kind: DirectiveKind,
+        pub name: String,
+        pub label: Option<String>,
+        /// `#id` is written as `id` and `.class`es are joined into `class`.
+        pub attributes: BTreeMap<String, String>,
+    }
+
+    /// Turns directives into custom blocks and inline objects, see
+    /// `Options::directive_handlers`. Directives a handler returns `None` for
+    /// are kept as text.
+    pub trait DirectiveHandler {
+        /// The block for a container or leaf directive. The blocks inside a
+        /// container are added as its `content`.
+        fn block(&self, _directive: &Directive) -> Option<BlockNode> {
+            None
+        }
+
+        /// The inline object for a text directive.
+        fn inline(&self, _directive: &Directive) -> Option<InlineObject> {
+            None
+        }
+    }
+
+    /// An object among the `children` of a block, like inline math. Its
+    /// fields are kept in `BlockNode::child_fields`.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct InlineObject {
+        pub _type: String,
+        pub fields: BTreeMap<String, Value>,
+    }
+
+    impl InlineObject {
+        pub fn new(_type: impl Into<String>) -> Self {
+            Self {
+                _type: _type.into(),
+                fields: BTreeMap::new(),
+            }
+        }
+    }
+
+    /// Extends the conversion. Plugins see the events before the converter
+    /// does, in the order they are listed in `Options::plugins`.
+    ///
+    /// Events the converter consumes while handling another event are not
+    /// passed to plugins: the text inside code blocks and image alt text,
+    /// read with `consume_inner`, and the `:::` and `::name` directive lines
+    /// and `[!NOTE]` alert markers it recognizes by looking ahead.
+    pub trait Plugin {
+        /// Returns `true` when the event is fully handled, which skips the
+        /// remaining plugins and the default handling.
+        fn event(&self, event: &Event<'_>, context: &mut PluginContext<'_>) -> io::Result<bool>;
+    }
+
+    /// The parts of the converter plugins can use, see `Plugin`.
+    pub struct PluginContext<'c> {
+        writer: &'c mut dyn Build,
+    }
+
+    impl PluginContext<'_> {
+        /// Starts a text block. Paragraphs inside the current construct, like
+        /// those of a list item or quote, continue it until `close_block`.
+        pub fn push_block(&mut self, block: BlockNode) -> io::Result<()> {
+            self.writer.push_block(block, true)
+        }
+
+        /// Adds a block that no text is added to, like a code block or a
+        /// custom object.
+        pub fn push_object(&mut self, block: BlockNode) -> io::Result<()> {
+            self.writer.push_block(block, false)
+        }
+
+        pub fn close_block(&mut self) -> io::Result<()> {
+            self.writer.close_block()
+        }
+
+        /// The last block of this conversion.
+        pub fn last_block(&mut self) -> Option<&mut BlockNode> {
+            self.writer.last_block()
+        }
+
+        /// Removes the last block, e.g. to replace it with an object.
+        pub fn pop_block(&mut self) -> Option<BlockNode> {
+            self.writer.pop_block()
+        }
+
+        /// Adds a span with the active marks to the last block.
+        pub fn push_text(&mut self, text: &str) -> io::Result<()> {
+            self.writer.push_span(text.to_owned(), "span".to_owned())
+        }
+
+        /// Adds a child to the last block as it is.
+        pub fn push_child(&mut self, child: SpanNode) {
+            if let Some(block) = self.writer.last_block() {
+                block.children.push(child);
+            }
+        }
+
+        /// Adds an inline object with the active marks to the last block.
+        pub fn push_inline_object(&mut self, object: InlineObject) -> io::Result<()> {
+            self.writer.push_inline_object(object)
+        }
+
+        /// Marks the text that follows, until `stop_mark`.
+        pub fn start_mark(&mut self, mark: Decorators) -> io::Result<()> {
+            self.writer.mark_start(mark)
+        }
+
+        /// Ends a mark started with `start_mark`. Fails if it is not active.
+        pub fn stop_mark(&mut self, mark: Decorators) -> io::Result<()> {
+            self.writer.mark_stop(mark)
+        }
+
+        /// Adds a mark def to the last block.
+        pub fn add_mark_def(&mut self, mark_def: MarkDef) -> io::Result<()> {
+            self.writer.add_mark_def(mark_def)
+        }
+
+        /// Starts a `MarkDef::Custom` annotation, returning its key.
+        pub fn open_annotation(
+            &mut self,
+            _type: String,
+            fields: BTreeMap<String, Value>,
+        ) -> io::Result<String> {
+            self.writer.open_annotation(_type, fields)
+        }
+
+        pub fn close_annotation(&mut self, _type: &str) -> io::Result<()> {
+            self.writer.close_annotation(_type)
+        }
+
+        /// Skips the events up to the end of the current tag, returning their
+        /// text. Call this when handling a `Start` event to replace the whole
+        /// construct.
+        pub fn consume_inner(&mut self) -> String {
+            self.writer.consume_inner()
+        }
+
+        /// Reports a `DiagnosticKind::Plugin` diagnostic.
+        pub fn warn(&mut self, message: impl Into<String>) {
+            self.writer.warn(DiagnosticKind::Plugin, message.into());
+        }
+
+        /// Byte range of the current event in the Markdown source, only known
+        /// when converting with `try_push_portabletext_with_offsets`.
+        pub fn source_position(&self) -> Option<Range<usize>> {
+            self.writer.position()
+        }
+    }
+
+    /// What `PluginContext` needs from `PortabletextWriter`, without its
+    /// event iterator type.
+    trait Build {
+        fn push_block(&mut self, block: BlockNode, open: bool) -> io::Result<()>;
+        fn close_block(&mut self) -> io::Result<()>;
+        fn last_block(&mut self) -> Option<&mut BlockNode>;
+        fn pop_block(&mut self) -> Option<BlockNode>;
+        fn push_span(&mut self, text: String, _type: String) -> io::Result<()>;
+        fn push_inline_object(&mut self, object: InlineObject) -> io::Result<()>;
+        fn mark_start(&mut self, decorator: Decorators) -> io::Result<()>;
+        fn mark_stop(&mut self, decorator: Decorators) -> io::Result<()>;
+        fn add_mark_def(&mut self, mark_def: MarkDef) -> io::Result<()>;
+        fn open_annotation(
+            &mut self,
+            _type: String,
+            fields: BTreeMap<String, Value>,
+        ) -> io::Result<String>;
+        fn close_annotation(&mut self, _type: &str) -> io::Result<()>;
+        fn consume_inner(&mut self) -> String;
+        fn warn(&mut self, kind: DiagnosticKind, message: String);
+        fn position(&self) -> Option<Range<usize>>;
+    }
+
+    /// What to do with an image the asset resolver fails on.
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum AssetErrorPolicy {
+        /// Abort the conversion with the `ResolveError`.
+        Error,
+        /// Keep the unresolved reference and report a `Diagnostic`.
+        Warn,
+        /// Leave the image out, alt text included.
+        Drop,
+    }
+
+    /// What to do with a link or image URL whose scheme is not allowed.
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum UnsafeUrlAction {
+        /// Remove the link annotation, or the image, and report a `Diagnostic`.
+        Strip,
+        /// Replace the URL, e.g. with `#`, and report a `Diagnostic`.
+        Rewrite(String),
+        /// Abort the conversion.
+        Reject,
+    }
+
+    /// An allowlist of URL schemes for links and images. Relative URLs have no
+    /// scheme and are always allowed.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct UrlPolicy {
+        /// Lowercase schemes, e.g. `https`.
+        pub allowed_schemes: Vec<String>,
+        pub on_unsafe: UnsafeUrlAction,
+    }
+
+    impl Default for UrlPolicy {
+        /// Allows `http`, `https`, `mailto` and `tel`, and strips anything else.
+        fn default() -> Self {
+            Self {
+                allowed_schemes: ["http", "https", "mailto", "tel"]
+                    .iter()
+                    .map(|scheme| scheme.to_string())
+                    .collect(),
+                on_unsafe: UnsafeUrlAction::Strip,
+            }
+        }
+    }
+
+    impl UrlPolicy {
+        pub fn allows(&self, url: &str) -> bool {
+            // browsers ignore these, so `java\tscript:` is still javascript
+            let cleaned: String = url
+                .trim_start_matches(|c: char| c <= ' ')
+                .chars()
+                .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+                .collect();
+            match url::scheme(&cleaned) {
+                Some(scheme) => self.allowed_schemes.contains(&scheme),
+                None => true,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum DiagnosticKind {
+        UnresolvedAsset,
+        UnresolvedLink,
+        UnsafeUrl,
+        /// A fenced block its `FenceHandler` could not turn into a block.
+        InvalidFence,
+        /// Reported by a `Plugin`.
+        Plugin,
+    }
+
+    /// A problem that did not stop the conversion.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct Diagnostic {
+        pub kind: DiagnosticKind,
+        pub message: String,
+        pub source_position: Option<Range<usize>>,
+    }
+
+    impl fmt::Display for Diagnostic {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match &self.source_position {
+                Some(position) => write!(f, "{} (at byte {})", self.message, position.start),
+                None => f.write_str(&self.message),
+            }
+        }
+    }
+
+    /// Style names for the blocks Markdown constructs turn into.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct StyleMap {
+        /// Paragraphs and list items.
+        pub normal: String,
+        pub blockquote: String,
+        /// Styles for heading levels, H1 first. Deeper headings get the last
+        /// style, e.g. `["title", "h2", "h3", "h4"]` turns H5 and H6 into `h4`.
+        pub headings: Vec<String>,
+        /// Added to each heading level before its style is looked up, e.g. 1
+        /// demotes all headings by one when the title comes from front matter.
+        pub heading_shift: i32,
+    }
+
+    impl Default for StyleMap {
+        fn default() -> Self {
+            Self {
+                normal: "normal".to_owned(),
+                blockquote: "blockquote".to_owned(),
+                headings: (1..=6).map(|level| format!("h{}", level)).collect(),
+                heading_shift: 0,
+            }
+        }
+    }
+
+    impl StyleMap {
+        /// The style of a Markdown heading, `level` 1 being H1.
+        pub fn heading(&self, level: u32) -> String {
+            let level = (level as i64 + i64::from(self.heading_shift)).max(1) as usize;
+            match self
+                .headings
+                .get(level - 1)
+                .or_else(|| self.headings.last())
+            {
+                Some(style) => style.to_owned(),
+                None => format!("h{}", level),
             }
         }
     }
 
+    #[derive(Clone)]
     pub struct Options<'a> {
         pub asset_resolver: &'a dyn AssetReferenceResolver,
+        pub on_asset_error: AssetErrorPolicy,
+        pub link_resolver: &'a dyn LinkResolver,
+        /// Checks the scheme of link and image URLs before they are resolved.
+        /// `None` allows any URL.
+        pub url_policy: Option<UrlPolicy>,
+        /// Hosts the content is published on, e.g. `example.com`, which also
+        /// covers its subdomains. Links to any other host are marked
+        /// `external` and `blank`. Empty to not mark any links.
+        pub site_hosts: Vec<String>,
+        pub styles: StyleMap,
+        /// Names to write decorators with instead of their default, e.g.
+        /// `Decorators::Strike` to `strike-through`. Renamed decorators are
+        /// written as `Decorators::Custom`.
+        pub decorator_names: HashMap<Decorators, String>,
+        pub annotation_handler: &'a dyn AnnotationHandler,
+        pub plugins: Vec<&'a dyn Plugin>,
+        /// Handlers for fenced blocks by language, the first word of the info
+        /// string.
+        pub fence_handlers: HashMap<String, &'a dyn FenceHandler>,
+        /// Turn quotes starting with a GitHub alert like `[!NOTE]`, and
+        /// `:::tip` containers, into `callout` objects with a `tone`, an
+        /// optional `title` and the blocks inside as `content`. Off by default,
+        /// which keeps them as quotes and paragraphs.
+        pub callouts: bool,
+        /// Handlers for `:::name` container, `::name` leaf and `:name` text
+        /// directives by name. They take precedence over `callouts`.
+        pub directive_handlers: HashMap<String, &'a dyn DirectiveHandler>,
+        /// Turn math rewritten by `math::prepare`, and ```` ```math ```` fences,
+        /// into `math` objects with the TeX source as `tex`.
+        /// `try_push_markdown` runs `math::prepare` itself; when passing a
+        /// parser, run it on the Markdown first.
+        pub math: bool,
+        /// Turn paragraphs of only a URL, like a YouTube video, into `embed`
+        /// objects when one of the providers matches it. Paragraphs in
+        /// callouts are embedded too, those in plain quotes are not.
+        #[cfg(feature = "embeds")]
+        pub embeds: Option<embeds::EmbedProviders>,
+        /// Run `normalize::normalize` over the converted blocks.
+        pub normalize: bool,
+        /// Give the converted blocks, and the blocks inside containers, a
+        /// random `_key` with `assign_keys`. `sanity::mutations` needs keys,
+        /// and `diff` pairs blocks by them.
+        pub keys: bool,
     }
 
     impl Default for Options<'_> {
         fn default() -> Self {
             Self {
                 asset_resolver: &IdentityResolver {},
+                on_asset_error: AssetErrorPolicy::Error,
+                link_resolver: &IdentityLinkResolver {},
+                url_policy: None,
+                site_hosts: vec![],
+                styles: StyleMap::default(),
+                decorator_names: HashMap::new(),
+                annotation_handler: &NoAnnotations {},
+                plugins: vec![],
+                fence_handlers: HashMap::new(),
+                callouts: false,
+                directive_handlers: HashMap::new(),
+                math: false,
+                #[cfg(feature = "embeds")]
+                embeds: None,
+                normalize: false,
+                keys: false,
             }
         }
     }
@@ -92,6 +653,55 @@ pub mod portabletext {
         #[cfg_attr(feature = "serde_serialization", serde(rename = "_type"))]
         pub _type: String,
         pub href: String,
+        /// Open in a new tab, set together with `external`.
+        #[cfg_attr(
+            feature = "serde_serialization",
+            serde(skip_serializing_if = "is_false")
+        )]
+        pub blank: bool,
+        /// Points to a host outside `Options::site_hosts`.
+        #[cfg_attr(
+            feature = "serde_serialization",
+            serde(skip_serializing_if = "is_false")
+        )]
+        pub external: bool,
+    }
+
+    #[cfg(feature = "serde_serialization")]
+    fn is_false(value: &bool) -> bool {
+        !*value
+    }
+
+    /// A link to another document, see `ResolvedLink::Internal`.
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde_serialization", derive(Serialize))]
+    #[cfg_attr(feature = "serde_serialization", serde(rename_all = "camelCase"))]
+    pub struct MarkDefInternalLink {
+        #[cfg_attr(feature = "serde_serialization", serde(rename = "_key"))]
+        pub _key: String,
+        #[cfg_attr(feature = "serde_serialization", serde(rename = "_type"))]
+        pub _type: String,
+        pub reference: Reference,
+    }
+
+    /// An annotation of any other `_type`, e.g. a comment or citation. The
+    /// fields are written next to `_key` and `_type`.
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde_serialization", derive(Serialize))]
+    pub struct MarkDefCustom {
+        #[cfg_attr(feature = "serde_serialization", serde(rename = "_key"))]
+        pub _key: String,
+        #[cfg_attr(feature = "serde_serialization", serde(rename = "_type"))]
+        pub _type: String,
+        #[cfg_attr(feature = "serde_serialization", serde(flatten))]
+        pub fields: BTreeMap<String, Value>,
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde_serialization", derive(Serialize))]
+    pub struct Reference {
+        #[cfg_attr(feature = "serde_serialization", serde(rename = "_ref"))]
+        pub _ref: String,
     }
 
     #[derive(Debug, PartialEq, Clone)]
@@ -109,6 +719,11 @@ pub mod portabletext {
             serde(skip_serializing_if = "Option::is_none")
         )]
         pub caption: Option<String>,
+        #[cfg_attr(
+            feature = "serde_serialization",
+            serde(skip_serializing_if = "Option::is_none")
+        )]
+        pub asset: Option<Asset>,
     }
 
     #[derive(Debug, PartialEq, Clone)]
@@ -117,27 +732,61 @@ pub mod portabletext {
     #[cfg_attr(feature = "serde_serialization", serde(untagged))]
     pub enum MarkDef {
         Link(MarkDefLink),
-        Image(MarkDefImage),
+        InternalLink(MarkDefInternalLink),
+        Image(Box<MarkDefImage>),
+        Custom(MarkDefCustom),
     }
 
+    impl MarkDef {
+        /// The `_key` marks refer to this definition by.
+        pub fn key(&self) -> &str {
+            match self {
+                MarkDef::Link(link) => &link._key,
+                MarkDef::InternalLink(link) => &link._key,
+                MarkDef::Image(image) => &image._key,
+                MarkDef::Custom(custom) => &custom._key,
+            }
+        }
+    }
+
+    /// Serialized as a plain reference: `src` is only written while there is
+    /// no `_ref` yet.
     #[derive(Debug, PartialEq, Clone)]
-    #[cfg_attr(feature = "serde_serialization", derive(Serialize))]
-    #[cfg_attr(feature = "serde_serialization", serde(rename_all = "camelCase"))]
     pub struct Asset {
-        #[cfg_attr(feature = "serde_serialization", serde(rename = "_ref"))]
+        pub _type: String,
         pub _ref: String,
         pub src: String,
     }
 
-    #[derive(Debug, PartialEq, Clone)]
+    #[cfg(feature = "serde_serialization")]
+    impl Serialize for Asset {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut map = serializer.serialize_map(None)?;
+            map.serialize_entry("_type", &self._type)?;
+            map.serialize_entry("_ref", &self._ref)?;
+            if self._ref.is_empty() {
+                map.serialize_entry("src", &self.src)?;
+            }
+            map.end()
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq, Hash, Clone)]
     pub enum Decorators {
         Emphasis,
         Strong,
         Strike,
         Underline,
         Code,
+        /// A decorator by name, e.g. `highlight`.
+        Custom(String),
         LinkReference(String),
         AssetReference(String),
+        /// The key of a `MarkDef::Custom`.
+        AnnotationReference(String),
     }
 
     #[derive(Debug, PartialEq, Clone, Copy)]
@@ -148,59 +797,112 @@ pub mod portabletext {
         Numbered,
     }
 
-    #[derive(Debug, PartialEq)]
-    #[cfg_attr(feature = "serde_serialization", derive(Serialize))]
-    #[cfg_attr(feature = "serde_serialization", serde(rename_all = "camelCase"))]
+    /// A span of text, or an inline object when `_type` is not `span`.
+    /// Inline objects are written without `text`, and without `marks` unless
+    /// they have some.
+    #[derive(Debug, PartialEq, Clone)]
     pub struct SpanNode {
-        #[cfg_attr(feature = "serde_serialization", serde(rename = "_type"))]
         pub _type: String,
         pub text: String,
         pub marks: Vec<Decorators>,
     }
 
-    #[derive(Debug, PartialEq)]
-    #[cfg_attr(feature = "serde_serialization", derive(Serialize))]
-    #[cfg_attr(feature = "serde_serialization", serde(rename_all = "camelCase"))]
+    impl SpanNode {
+        pub fn span(text: String, marks: Vec<Decorators>) -> Self {
+            Self {
+                _type: "span".to_string(),
+                text,
+                marks,
+            }
+        }
+
+        /// An inline object of any `_type`, without text.
+        pub fn object(_type: String) -> Self {
+            Self {
+                _type,
+                text: String::new(),
+                marks: vec![],
+            }
+        }
+
+        #[cfg(feature = "serde_serialization")]
+        fn serialize_entries<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+            map.serialize_entry("_type", &self._type)?;
+            if self._type == "span" {
+                map.serialize_entry("text", &self.text)?;
+                map.serialize_entry("marks", &self.marks)?;
+            } else if !self.marks.is_empty() {
+                map.serialize_entry("marks", &self.marks)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "serde_serialization")]
+    impl Serialize for SpanNode {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut map = serializer.serialize_map(None)?;
+            self.serialize_entries(&mut map)?;
+            map.end()
+        }
+    }
+
+    /// A child with the fields of its inline object, if any.
+    #[cfg(feature = "serde_serialization")]
+    struct Child<'b> {
+        span: &'b SpanNode,
+        fields: Option<&'b BTreeMap<String, Value>>,
+    }
+
+    #[cfg(feature = "serde_serialization")]
+    impl Serialize for Child<'_> {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut map = serializer.serialize_map(None)?;
+            self.span.serialize_entries(&mut map)?;
+            for (name, value) in self.fields.into_iter().flatten() {
+                if !RESERVED_FIELDS.contains(&name.as_str()) {
+                    map.serialize_entry(name, value)?;
+                }
+            }
+            map.end()
+        }
+    }
+
+    /// Serialized with the fields of custom objects next to `_type`, and
+    /// `markDefs` on text blocks (or when there are any).
+    #[derive(Debug, PartialEq, Clone)]
     pub struct BlockNode {
-        #[cfg_attr(feature = "serde_serialization", serde(rename = "_type"))]
         pub _type: String,
-        #[cfg_attr(
-            feature = "serde_serialization",
-            serde(skip_serializing_if = "Option::is_none")
-        )]
+        pub _key: Option<String>,
         pub style: Option<String>,
 
-        #[cfg_attr(
-            feature = "serde_serialization",
-            serde(skip_serializing_if = "Vec::is_empty")
-        )]
         pub children: Vec<SpanNode>,
 
         // meta on marks
         pub mark_defs: Vec<MarkDef>,
         // list items
-        #[cfg_attr(
-            feature = "serde_serialization",
-            serde(skip_serializing_if = "Option::is_none")
-        )]
         pub level: Option<usize>,
-        #[cfg_attr(
-            feature = "serde_serialization",
-            serde(skip_serializing_if = "Option::is_none")
-        )]
         pub list_item: Option<ListItemType>,
 
-        #[cfg_attr(
-            feature = "serde_serialization",
-            serde(skip_serializing_if = "Option::is_none")
-        )]
         pub language: Option<String>,
 
-        #[cfg_attr(
-            feature = "serde_serialization",
-            serde(skip_serializing_if = "Option::is_none")
-        )]
         pub code: Option<String>,
+
+        /// Blocks inside a container object, like the text of a callout.
+        pub content: Vec<BlockNode>,
+
+        /// Fields of custom block objects, written next to `_type`. Fields
+        /// named like one of the above are not written.
+        pub fields: BTreeMap<String, Value>,
+
+        /// Fields of the inline objects in `children`, by their index there.
+        pub child_fields: BTreeMap<usize, BTreeMap<String, Value>>,
     }
 
     // TODO: split this into multiple types
@@ -208,6 +910,7 @@ pub mod portabletext {
         pub fn default(style: String) -> Self {
             Self {
                 _type: "block".to_string(),
+                _key: None,
                 style: Some(style),
                 children: vec![],
                 mark_defs: vec![],
@@ -215,12 +918,16 @@ pub mod portabletext {
                 code: None,
                 level: None,
                 list_item: None,
+                content: vec![],
+                fields: BTreeMap::new(),
+                child_fields: BTreeMap::new(),
             }
         }
 
         pub fn code(language: String, code: String) -> Self {
             Self {
                 _type: "code".to_string(),
+                _key: None,
                 style: None,
                 children: vec![],
                 mark_defs: vec![],
@@ -228,12 +935,34 @@ pub mod portabletext {
                 code: Some(code),
                 level: None,
                 list_item: None,
+                content: vec![],
+                fields: BTreeMap::new(),
+                child_fields: BTreeMap::new(),
+            }
+        }
+
+        /// A block object of any `_type`, without text.
+        pub fn object(_type: String) -> Self {
+            Self {
+                _type,
+                _key: None,
+                style: None,
+                children: vec![],
+                mark_defs: vec![],
+                language: None,
+                code: None,
+                level: None,
+                list_item: None,
+                content: vec![],
+                fields: BTreeMap::new(),
+                child_fields: BTreeMap::new(),
             }
         }
 
         pub fn default_list_item(level: usize, list_item: ListItemType) -> Self {
             Self {
                 _type: "block".to_string(),
+                _key: None,
                 style: Some("normal".to_string()),
                 level: Some(level),
                 list_item: Some(list_item),
@@ -241,6 +970,9 @@ pub mod portabletext {
                 mark_defs: vec![],
                 language: None,
                 code: None,
+                content: vec![],
+                fields: BTreeMap::new(),
+                child_fields: BTreeMap::new(),
             }
         }
 
@@ -248,30 +980,158 @@ pub mod portabletext {
             self.children = children;
             self
         }
+
+        pub fn with_key(mut self, key: String) -> Self {
+            self._key = Some(key);
+            self
+        }
+    }
+
+    #[cfg(feature = "serde_serialization")]
+    impl Serialize for BlockNode {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut map = serializer.serialize_map(None)?;
+            map.serialize_entry("_type", &self._type)?;
+            if let Some(key) = &self._key {
+                map.serialize_entry("_key", key)?;
+            }
+            if let Some(style) = &self.style {
+                map.serialize_entry("style", style)?;
+            }
+            if !self.children.is_empty() {
+                let children: Vec<Child> = self
+                    .children
+                    .iter()
+                    .enumerate()
+                    .map(|(index, span)| Child {
+                        span,
+                        fields: self.child_fields.get(&index),
+                    })
+                    .collect();
+                map.serialize_entry("children", &children)?;
+            }
+            if self._type == "block" || !self.mark_defs.is_empty() {
+                map.serialize_entry("markDefs", &self.mark_defs)?;
+            }
+            if let Some(level) = &self.level {
+                map.serialize_entry("level", level)?;
+            }
+            if let Some(list_item) = &self.list_item {
+                map.serialize_entry("listItem", list_item)?;
+            }
+            if let Some(language) = &self.language {
+                map.serialize_entry("language", language)?;
+            }
+            if let Some(code) = &self.code {
+                map.serialize_entry("code", code)?;
+            }
+            if !self.content.is_empty() {
+                map.serialize_entry("content", &self.content)?;
+            }
+            for (name, value) in &self.fields {
+                if !BLOCK_FIELDS.contains(&name.as_str()) {
+                    map.serialize_entry(name, value)?;
+                }
+            }
+            map.end()
+        }
+    }
+
+    #[cfg(feature = "serde_serialization")]
+    const BLOCK_FIELDS: &[&str] = &[
+        "_type", "_key", "style", "children", "markDefs", "level", "listItem", "language", "code",
+        "content",
+    ];
+
+    /// Names of the fields blocks, spans and mark defs write themselves.
+    /// Custom fields by these names are dropped or rejected, so they can't
+    /// turn into duplicate JSON keys.
+    pub const RESERVED_FIELDS: &[&str] = &[
+        "_type", "_key", "text", "marks", "markDefs", "style", "children", "content",
+    ];
+
+    /// A random key as used for `_key` fields.
+    pub fn random_key() -> String {
+        thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect()
+    }
+
+    /// Gives blocks without a `_key`, including those inside containers, a
+    /// random one.
+    pub fn assign_keys(blocks: &mut [BlockNode]) {
+        for block in blocks {
+            if block._key.is_none() {
+                block._key = Some(random_key());
+            }
+            assign_keys(&mut block.content);
+        }
+    }
+
+    /// A container object whose content is still being written.
+    struct OpenContainer {
+        block: BlockNode,
+        // index of its first content block
+        start: usize,
+        // the quote depth it was opened at
+        quote_depth: usize,
+        // the `:::name` line of `:::` containers, `None` for alert quotes
+        fence: Option<String>,
+        // whether the paragraph went on after the `:::name` line
+        continued: bool,
     }
 
     struct PortabletextWriter<'a, I> {
         iter: I,
         writer: &'a mut Vec<BlockNode>,
+        // index of the first block written by this writer
+        first_block: usize,
         open_block: bool,
         active_list_item: Vec<ListItemType>,
         list_item_level: usize,
         active_markers: Vec<Decorators>,
+        // keys of the links currently open, innermost last, `None` for
+        // stripped links
+        link_keys: Vec<Option<String>>,
+        // `_type` and key of the custom annotations currently open
+        open_annotations: Vec<(String, String)>,
+        // events looked ahead at, handled before those of `iter`
+        pending: VecDeque<(Event<'a>, Option<Range<usize>>)>,
+        // callouts currently open, innermost last
+        containers: Vec<OpenContainer>,
+        // number of block quotes the current event is in
+        quote_depth: usize,
         options: Options<'a>,
+        // source range of the event being handled, if known
+        position: Option<Range<usize>>,
+        diagnostics: Vec<Diagnostic>,
     }
     impl<'a, I> PortabletextWriter<'a, I>
     where
-        I: Iterator<Item = Event<'a>>,
+        I: Iterator<Item = (Event<'a>, Option<Range<usize>>)>,
     {
         fn new(iter: I, writer: &'a mut Vec<BlockNode>, options: Options<'a>) -> Self {
             Self {
                 iter,
+                first_block: writer.len(),
                 writer,
                 options,
                 open_block: false,
                 active_markers: Vec::with_capacity(3),
+                link_keys: vec![],
+                open_annotations: vec![],
+                pending: VecDeque::new(),
+                containers: vec![],
+                quote_depth: 0,
                 active_list_item: Vec::with_capacity(5),
                 list_item_level: 0,
+                position: None,
+                diagnostics: vec![],
             }
         }
 
@@ -284,8 +1144,19 @@ pub mod portabletext {
             Ok(())
         }
 
-        pub fn run(mut self) -> io::Result<()> {
-            while let Some(event) = self.iter.next() {
+        /// Writes a block text is never added to. Its end tag, if any, must
+        /// be consumed already.
+        fn write_object(&mut self, block: BlockNode) -> io::Result<()> {
+            self.writer.push(block);
+            self.close_block()
+        }
+
+        pub fn run(mut self) -> io::Result<Vec<Diagnostic>> {
+            while let Some((event, position)) = self.next_event() {
+                self.position = position;
+                if self.run_plugins(&event)? {
+                    continue;
+                }
                 match event {
                     Start(tag) => {
                         self.start_tag(tag)?;
@@ -294,41 +1165,371 @@ pub mod portabletext {
                         self.end_tag(tag)?;
                     }
                     Text(text) => {
-                        let no_marks = self.active_markers.to_vec().is_empty();
-                        if let Some(last_span) = self.last_span() {
-                            if last_span.marks.is_empty() && no_marks {
-                                self.add_text(text)?;
-                            } else {
-                                self.add_span(text)?;
-                            }
+                        if !self.options.directive_handlers.is_empty() && text.contains(':') {
+                            self.text_directives(text)?;
                         } else {
-                            self.add_span(text)?;
+                            self.text(text)?;
                         }
                     }
-                    Code(code) => {
-                        self.mark_start(Decorators::Code)?;
-                        self.add_span(code)?;
-                        self.mark_stop(Decorators::Code)?;
-                    }
+                    Code(code) if self.options.math => match math::inline_tex(&code) {
+                        Some(tex) => self.push_inline_object(math::inline(tex))?,
+                        None => self.code_span(math::unescape_code(&code).into())?,
+                    },
+                    Code(code) => self.code_span(code)?,
                     SoftBreak => {
+                        if self.close_container_fence()? {
+                            continue;
+                        }
                         if let Some(last_span) = self.last_span() {
-                            if last_span.marks.is_empty() {
+                            if last_span.marks.is_empty() && last_span._type == "span" {
                                 last_span.text += " ";
                             } else {
                                 self.add_span(" ".into())?;
                             }
                         }
                     }
-                    Html(_) | FootnoteReference(_) | Rule | HardBreak | TaskListMarker(_) => {}
+                    // only inline HTML inside text, not HTML blocks between blocks
+                    Html(html) if self.open_block => {
+                        match self.options.annotation_handler.inline_html(&html) {
+                            Some(AnnotationEvent::Open { _type, fields }) => {
+                                let fields = self.annotation_fields(fields)?;
+                                self.open_annotation(_type, fields)?;
+                            }
+                            Some(AnnotationEvent::Close { _type }) => {
+                                self.close_annotation(&_type)?;
+                            }
+                            None => {}
+                        }
+                    }
+                    Html(_) => {}
+                    FootnoteReference(_) | Rule | HardBreak | TaskListMarker(_) => {}
+                }
+            }
+
+            while !self.containers.is_empty() {
+                if self.in_fenced_container() {
+                    self.unwind_container()?;
+                } else {
+                    self.close_container()?;
+                }
+            }
+            if self.options.normalize {
+                normalize::normalize(&mut self.writer[self.first_block..]);
+            }
+            if self.options.keys {
+                assign_keys(&mut self.writer[self.first_block..]);
+            }
+            Ok(self.diagnostics)
+        }
+
+        /// Whether a plugin handled the event.
+        fn run_plugins(&mut self, event: &Event<'a>) -> io::Result<bool> {
+            for index in 0..self.options.plugins.len() {
+                let plugin = self.options.plugins[index];
+                let mut context = PluginContext { writer: self };
+                if plugin.event(event, &mut context)? {
+                    return Ok(true);
                 }
             }
+            Ok(false)
+        }
+
+        fn next_event(&mut self) -> Option<(Event<'a>, Option<Range<usize>>)> {
+            self.pending.pop_front().or_else(|| self.iter.next())
+        }
+
+        /// The event `offset` events ahead, without consuming it.
+        fn peek_event(&mut self, offset: usize) -> Option<&Event<'a>> {
+            while self.pending.len() <= offset {
+                let event = self.iter.next()?;
+                self.pending.push_back(event);
+            }
+            self.pending.get(offset).map(|(event, _)| event)
+        }
+
+        /// The text of the line starting `offset` events ahead and the number
+        /// of its events, if the line is only text. Lines end at a break or at
+        /// the end of a paragraph or of a tight list item.
+        fn peek_line(&mut self, offset: usize) -> Option<(String, usize)> {
+            let mut line = String::new();
+            let mut length = 0;
+            loop {
+                match self.peek_event(offset + length)? {
+                    Text(text) => line.push_str(text),
+                    SoftBreak | HardBreak | End(Tag::Paragraph) | End(Tag::Item) if length > 0 => {
+                        return Some((line, length))
+                    }
+                    _ => return None,
+                }
+                length += 1;
+            }
+        }
+
+        fn skip_events(&mut self, count: usize) {
+            for _ in 0..count {
+                self.next_event();
+            }
+        }
+
+        /// Skips the line break or paragraph end after a consumed line, but
+        /// leaves the end of a list item to close the item. Returns whether
+        /// the paragraph continues with another line.
+        fn skip_line_end(&mut self) -> bool {
+            match self.peek_event(0) {
+                Some(SoftBreak) | Some(HardBreak) => {
+                    self.next_event();
+                    true
+                }
+                Some(End(Tag::Paragraph)) => {
+                    self.next_event();
+                    false
+                }
+                _ => false,
+            }
+        }
+
+        /// Opens a container, with the `:::name` line it starts at for `:::`
+        /// containers. Returns whether the paragraph continues after the
+        /// line.
+        fn open_container(&mut self, block: BlockNode, fence: Option<String>) -> io::Result<bool> {
+            self.close_block()?;
+            let continued = self.skip_line_end();
+            self.containers.push(OpenContainer {
+                block,
+                start: self.writer.len(),
+                quote_depth: self.quote_depth,
+                fence,
+                continued,
+            });
+            Ok(continued)
+        }
+
+        fn close_container(&mut self) -> io::Result<()> {
+            self.close_block()?;
+            if let Some(container) = self.containers.pop() {
+                let mut block = container.block;
+                block.content = self.writer.split_off(container.start);
+                self.write_object(block)?;
+            }
             Ok(())
         }
 
+        /// Drops the innermost `:::` container, which is never closed, and
+        /// leaves its blocks as they are, after its `:::name` line as text.
+        fn unwind_container(&mut self) -> io::Result<()> {
+            self.close_block()?;
+            let container = match self.containers.pop() {
+                Some(container) => container,
+                None => return Ok(()),
+            };
+            let line = container.fence.unwrap_or_default();
+            match self.writer.get_mut(container.start) {
+                Some(block) if container.continued && block._type == "block" => {
+                    block
+                        .children
+                        .insert(0, SpanNode::span(format!("{} ", line), vec![]));
+                    block.child_fields = mem::take(&mut block.child_fields)
+                        .into_iter()
+                        .map(|(index, fields)| (index + 1, fields))
+                        .collect();
+                }
+                _ => {
+                    let mut block = BlockNode::default(self.options.styles.normal.to_owned());
+                    block.children.push(SpanNode::span(line, vec![]));
+                    self.writer.insert(container.start, block);
+                }
+            }
+            Ok(())
+        }
+
+        fn text(&mut self, text: CowStr<'a>) -> io::Result<()> {
+            let no_marks = self.active_markers.is_empty();
+            if let Some(last_span) = self.last_span() {
+                if last_span.marks.is_empty() && no_marks && last_span._type == "span" {
+                    self.add_text(text)?;
+                } else {
+                    self.add_span(text)?;
+                }
+            } else {
+                self.add_span(text)?;
+            }
+            Ok(())
+        }
+
+        /// Writes text with its `:name[label]` directives turned into inline
+        /// objects by their handlers.
+        fn text_directives(&mut self, text: CowStr<'a>) -> io::Result<()> {
+            // labels and attributes arrive as separate text events
+            let mut text = text.into_string();
+            while let Some(Text(next)) = self.peek_event(0) {
+                text.push_str(next);
+                self.next_event();
+            }
+
+            let mut rest = text.as_str();
+            while let Some((start, end, directive)) = directives::find_text_directive(rest) {
+                let handler = self.options.directive_handlers.get(&directive.name);
+                match handler.and_then(|handler| handler.inline(&directive)) {
+                    Some(object) => {
+                        if start > 0 {
+                            self.text(rest[..start].to_owned().into())?;
+                        }
+                        self.push_inline_object(object)?;
+                    }
+                    None => self.text(rest[..end].to_owned().into())?,
+                }
+                rest = &rest[end..];
+            }
+            if !rest.is_empty() {
+                self.text(rest.to_owned().into())?;
+            }
+            Ok(())
+        }
+
+        /// The block the handler registered for a container or leaf
+        /// directive, or the callouts, make of it.
+        fn directive_block(&self, directive: &Directive) -> Option<BlockNode> {
+            match self.options.directive_handlers.get(&directive.name) {
+                Some(handler) => handler.block(directive),
+                None if self.options.callouts => directives::Callouts.block(directive),
+                None => None,
+            }
+        }
+
+        /// Handles a paragraph starting with a `:::name` line, which opens a
+        /// container, or a `:::` line, which closes one, and paragraphs of
+        /// only a `::name` leaf directive. Returns whether the paragraph is
+        /// fully handled.
+        fn directive_paragraph(&mut self) -> io::Result<bool> {
+            let (line, length) = match self.peek_line(0) {
+                Some(line) => line,
+                None => return Ok(false),
+            };
+
+            if let Some(directive) = directives::parse_leaf(&line) {
+                let whole_paragraph = matches!(self.peek_event(length), Some(End(Tag::Paragraph)));
+                return match self.directive_block(&directive) {
+                    Some(block) if whole_paragraph => {
+                        self.skip_events(length + 1);
+                        self.write_object(block)?;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                };
+            }
+
+            match directives::parse_container_fence(&line) {
+                Some(directives::ContainerFence::Open(directive)) => {
+                    let block = match self.directive_block(&directive) {
+                        Some(block) => block,
+                        None => return Ok(false),
+                    };
+                    self.skip_events(length);
+                    Ok(!self.open_container(block, Some(line))?)
+                }
+                Some(directives::ContainerFence::Close) if self.in_fenced_container() => {
+                    self.skip_events(length);
+                    self.close_container()?;
+                    Ok(!self.skip_line_end())
+                }
+                _ => Ok(false),
+            }
+        }
+
+        /// Closes the innermost `:::` container at a `:::` line inside a
+        /// paragraph. The rest of the paragraph, if any, starts a new block.
+        fn close_container_fence(&mut self) -> io::Result<bool> {
+            if !self.in_fenced_container() {
+                return Ok(false);
+            }
+            match self.peek_line(0) {
+                Some((line, length))
+                    if directives::parse_container_fence(&line)
+                        == Some(directives::ContainerFence::Close) =>
+                {
+                    self.skip_events(length);
+                    self.end_paragraph()?;
+                    self.close_container()?;
+                    if self.skip_line_end() {
+                        self.write(BlockNode::default(self.options.styles.normal.to_owned()))?;
+                    }
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        }
+
+        fn in_fenced_container(&self) -> bool {
+            matches!(self.containers.last(), Some(container) if container.fence.is_some())
+        }
+
+        fn end_paragraph(&mut self) -> io::Result<()> {
+            self.close_block()?;
+            #[cfg(feature = "embeds")]
+            self.embed_paragraph()?;
+            Ok(())
+        }
+
+        /// Replaces the paragraph just written with an `embed` object if it
+        /// is only a URL one of `Options::embeds` matches.
+        #[cfg(feature = "embeds")]
+        fn embed_paragraph(&mut self) -> io::Result<()> {
+            // outside quotes, or right inside an alert quote
+            let callout_depth = self
+                .containers
+                .iter()
+                .rev()
+                .find(|container| container.fence.is_none())
+                .map_or(0, |container| container.quote_depth);
+            let providers = match &self.options.embeds {
+                Some(providers) if self.quote_depth == callout_depth => providers,
+                _ => return Ok(()),
+            };
+            let block = match self.writer.get(self.first_block..).and_then(<[_]>::last) {
+                Some(block) => block,
+                None => return Ok(()),
+            };
+            if block._type != "block"
+                || block.list_item.is_some()
+                || block.style.as_ref() != Some(&self.options.styles.normal)
+            {
+                return Ok(());
+            }
+
+            let embed = embeds::standalone_url(block).and_then(|url| providers.embed(&url));
+            if let Some(embed) = embed {
+                self.writer.pop();
+                self.write_object(embed)?;
+            }
+            Ok(())
+        }
+
+        /// Opens a callout for a quote starting with a `[!NOTE]` line.
+        /// Returns whether the quote was one.
+        fn alert_quote(&mut self) -> io::Result<bool> {
+            if !self.options.callouts || !matches!(self.peek_event(0), Some(Start(Tag::Paragraph)))
+            {
+                return Ok(false);
+            }
+            let (tone, length) = match self.peek_line(1) {
+                Some((line, length)) => match directives::alert_tone(&line) {
+                    Some(tone) => (tone, length),
+                    None => return Ok(false),
+                },
+                None => return Ok(false),
+            };
+
+            self.skip_events(1 + length);
+            if self.open_container(directives::callout(tone), None)? {
+                self.write(BlockNode::default(self.options.styles.normal.to_owned()))?;
+            }
+            Ok(true)
+        }
+
         fn consume_inner(&mut self) -> String {
             let mut nest = 0;
             let mut buffer = String::new();
-            for event in &mut self.iter {
+            while let Some((event, _)) = self.next_event() {
                 match event {
                     Start(_) => nest += 1,
                     End(_) => {
@@ -353,24 +1554,47 @@ pub mod portabletext {
         fn start_tag(&mut self, tag: Tag<'a>) -> io::Result<()> {
             match tag {
                 Tag::Paragraph => {
-                    if !self.open_block {
-                        self.write(BlockNode::default("normal".to_string()))
+                    if self.directive_paragraph()? {
+                        Ok(())
+                    } else if !self.open_block {
+                        self.write(BlockNode::default(self.options.styles.normal.to_owned()))
                     } else {
                         Ok(())
                     }
                 }
-                Tag::BlockQuote => self.write(BlockNode::default("blockquote".to_string())),
+                Tag::BlockQuote => {
+                    self.quote_depth += 1;
+                    if self.alert_quote()? {
+                        return Ok(());
+                    }
+                    self.write(BlockNode::default(
+                        self.options.styles.blockquote.to_owned(),
+                    ))
+                }
                 Tag::CodeBlock(CodeBlockKind::Fenced(syntax)) => {
                     let code = self.consume_inner();
-                    self.write(BlockNode::code(syntax.into_string(), code))
+                    let language = syntax.split_whitespace().next().unwrap_or("");
+                    if let Some(handler) = self.options.fence_handlers.get(language).copied() {
+                        match fences::fence_block(handler, &syntax, &code) {
+                            Ok(block) => return self.write_object(block),
+                            Err(message) => {
+                                let message = format!("invalid {} block: {}", language, message);
+                                Build::warn(self, DiagnosticKind::InvalidFence, message);
+                            }
+                        }
+                    } else if self.options.math && language == "math" {
+                        let tex = code.trim_end_matches('\n');
+                        return self.write_object(math::block(tex));
+                    }
+                    self.write_object(BlockNode::code(syntax.into_string(), code))
                 }
                 Tag::CodeBlock(CodeBlockKind::Indented) => {
                     let plain_text: String = "plain_text".to_owned();
                     let code = self.consume_inner();
-                    self.write(BlockNode::code(plain_text, code))
+                    self.write_object(BlockNode::code(plain_text, code))
                 }
                 Tag::Heading(level) => {
-                    let styling = format!("h{}", level);
+                    let styling = self.options.styles.heading(level);
                     self.write(BlockNode::default(styling))
                 }
                 Tag::List(options) => {
@@ -383,53 +1607,128 @@ pub mod portabletext {
                     Ok(())
                 }
                 Tag::Item => {
-                    let list_item = *self.active_list_item.last().unwrap();
+                    let list_item = *self.active_list_item.last().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "list item outside a list")
+                    })?;
                     let level = self.list_item_level;
-                    self.write(BlockNode::default_list_item(level, list_item))
+                    let mut block = BlockNode::default_list_item(level, list_item);
+                    block.style = Some(self.options.styles.normal.to_owned());
+                    self.write(block)
                 }
-                Tag::Link(_link_type, link_href, _link_title) => {
-                    let key: String = thread_rng()
-                        .sample_iter(&Alphanumeric)
-                        .take(12)
-                        .map(char::from)
-                        .collect();
-                    let mark_def = MarkDef::Link(MarkDefLink {
-                        _type: "link".to_owned(),
-                        _key: key.to_owned(),
-                        href: link_href.to_string(),
-                    });
+                Tag::Link(_link_type, link_href, link_title) => {
+                    let link_href = match self.check_url(link_href)? {
+                        Some(link_href) => link_href,
+                        None => {
+                            self.link_keys.push(None);
+                            return Ok(());
+                        }
+                    };
+                    let key = random_key();
+                    let context = LinkContext {
+                        href: &link_href,
+                        title: if !link_title.is_empty() {
+                            Some(&link_title)
+                        } else {
+                            None
+                        },
+                        block: self.writer.last(),
+                        source_position: self.position.clone(),
+                    };
+
+                    let resolved = match self.options.link_resolver.resolve_link(&context) {
+                        Ok(resolved) => resolved,
+                        Err(error) => {
+                            self.diagnostics.push(Diagnostic {
+                                kind: DiagnosticKind::UnresolvedLink,
+                                message: error.to_string(),
+                                source_position: context.source_position.clone(),
+                            });
+                            ResolvedLink::Href(link_href.to_string())
+                        }
+                    };
+                    let mark_def = match resolved {
+                        ResolvedLink::Href(href) => {
+                            let external = !self.options.site_hosts.is_empty()
+                                && links::is_external(&href, &self.options.site_hosts);
+                            MarkDef::Link(MarkDefLink {
+                                _type: "link".to_owned(),
+                                _key: key.to_owned(),
+                                href,
+                                blank: external,
+                                external,
+                            })
+                        }
+                        ResolvedLink::Internal(id) => MarkDef::InternalLink(MarkDefInternalLink {
+                            _type: "internalLink".to_owned(),
+                            _key: key.to_owned(),
+                            reference: Reference { _ref: id },
+                        }),
+                    };
 
-                    self.add_mark_def(mark_def).unwrap();
+                    self.add_mark_def(mark_def)?;
+                    self.link_keys.push(Some(key.to_owned()));
                     self.mark_start(Decorators::LinkReference(key))
                 }
                 Tag::Image(_image_type, image_href, title) => {
-                    let key: String = thread_rng()
-                        .sample_iter(&Alphanumeric)
-                        .take(12)
-                        .map(char::from)
-                        .collect();
+                    let key = random_key();
+                    let source_position = self.position.clone();
 
                     let alt = self.consume_inner();
-                    let src = self.options.asset_resolver.resolve(image_href.to_string());
-                    let picture = self
-                        .options
-                        .asset_resolver
-                        .resolve_picture(image_href.to_string(), alt.to_owned());
-
-                    if let Some(last_block) = self.last_block() {
-                        let caption = if !title.is_empty() {
-                            Some(title.to_string())
+                    let image_href = match self.check_url(image_href)? {
+                        Some(image_href) => image_href,
+                        None => return Ok(()),
+                    };
+                    let context = ImageContext {
+                        reference: &image_href,
+                        alt: &alt,
+                        title: if !title.is_empty() {
+                            Some(&title)
                         } else {
                             None
-                        };
+                        },
+                        block: self.writer.last(),
+                        source_position,
+                    };
+                    let caption = context.title.map(str::to_owned);
 
-                        last_block.mark_defs.push(MarkDef::Image(MarkDefImage {
-                            _key: key.to_owned(),
-                            _type: "image".to_owned(),
-                            src,
-                            picture,
-                            caption,
-                        }));
+                    let resolver = self.options.asset_resolver;
+                    let resolved = resolver.resolve(&context).and_then(|src| {
+                        let picture = resolver.resolve_picture(&context)?;
+                        Ok((src, picture, resolver.resolve_asset(&context)?))
+                    });
+                    let (src, picture, asset) = match resolved {
+                        Ok(resolved) => resolved,
+                        Err(error) => match self.options.on_asset_error {
+                            AssetErrorPolicy::Error => {
+                                return Err(io::Error::new(io::ErrorKind::NotFound, error))
+                            }
+                            AssetErrorPolicy::Warn => {
+                                self.diagnostics.push(Diagnostic {
+                                    kind: DiagnosticKind::UnresolvedAsset,
+                                    message: error.to_string(),
+                                    source_position: context.source_position.clone(),
+                                });
+                                (
+                                    IdentityResolver.resolve(&context).unwrap(),
+                                    IdentityResolver.resolve_picture(&context).unwrap(),
+                                    None,
+                                )
+                            }
+                            AssetErrorPolicy::Drop => return Ok(()),
+                        },
+                    };
+
+                    if let Some(last_block) = self.last_block() {
+                        last_block
+                            .mark_defs
+                            .push(MarkDef::Image(Box::new(MarkDefImage {
+                                _key: key.to_owned(),
+                                _type: "image".to_owned(),
+                                src,
+                                picture,
+                                caption,
+                                asset,
+                            })));
 
                         self.mark_start(Decorators::AssetReference(key.to_owned()))?;
                         self.add_span_with_type(alt.into(), "figure".to_owned())?;
@@ -456,37 +1755,99 @@ pub mod portabletext {
                 Tag::Strong => self.mark_stop(Decorators::Strong),
                 Tag::Emphasis => self.mark_stop(Decorators::Emphasis),
                 Tag::Strikethrough => self.mark_stop(Decorators::Strike),
-                Tag::Link(_link_type, link_url, _link_title) => {
-                    let mark_def = self
-                        .last_block()
-                        .unwrap()
-                        .mark_defs
-                        .iter()
-                        .find(|d| {
-                            if let MarkDef::Link(l) = d {
-                                l.href == link_url.to_string()
-                            } else {
-                                false
-                            }
-                        })
-                        .unwrap_or_else(|| panic!("mark def missing for {}", link_url));
-
-                    let key = match mark_def {
-                        MarkDef::Link(l) => l._key.as_str().to_owned(),
-                        _ => panic!("Cannot find key for link"),
-                    };
-
-                    self.mark_stop(Decorators::LinkReference(key))
+                Tag::Link(_link_type, link_url, _link_title) => match self.link_keys.pop() {
+                    Some(Some(key)) => self.mark_stop(Decorators::LinkReference(key)),
+                    Some(None) => Ok(()),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("link {} is not open", link_url),
+                    )),
+                },
+                Tag::BlockQuote => {
+                    // `:::` containers end with the quote they are in
+                    while matches!(
+                        self.containers.last(),
+                        Some(container) if container.fence.is_some()
+                            && container.quote_depth >= self.quote_depth
+                    ) {
+                        self.unwind_container()?;
+                    }
+                    let alert = matches!(
+                        self.containers.last(),
+                        Some(container) if container.fence.is_none()
+                            && container.quote_depth == self.quote_depth
+                    );
+                    self.quote_depth -= 1;
+                    if alert {
+                        self.close_container()
+                    } else {
+                        self.close_block()
+                    }
                 }
                 Tag::List(_options) => {
                     self.active_list_item.pop();
-                    self.list_item_level -= 1;
+                    self.list_item_level = self.list_item_level.saturating_sub(1);
                     Ok(())
                 }
+                Tag::Paragraph => self.end_paragraph(),
                 _ => self.close_block(),
             }
         }
 
+        /// Applies `options.url_policy` to a link or image URL. `None` means
+        /// the URL was stripped.
+        fn check_url(&mut self, url: CowStr<'a>) -> io::Result<Option<CowStr<'a>>> {
+            let policy = match &self.options.url_policy {
+                Some(policy) if !policy.allows(&url) => policy,
+                _ => return Ok(Some(url)),
+            };
+
+            let (message, url) = match &policy.on_unsafe {
+                UnsafeUrlAction::Strip => (format!("removed unsafe URL {}", url), None),
+                UnsafeUrlAction::Rewrite(replacement) => (
+                    format!("replaced unsafe URL {} with {}", url, replacement),
+                    Some(replacement.to_owned().into()),
+                ),
+                UnsafeUrlAction::Reject => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsafe URL {}", url),
+                    ))
+                }
+            };
+            self.diagnostics.push(Diagnostic {
+                kind: DiagnosticKind::UnsafeUrl,
+                message,
+                source_position: self.position.clone(),
+            });
+            Ok(url)
+        }
+
+        /// Drops reserved fields of an annotation from inline HTML and checks
+        /// URL attributes like `href` against `Options::url_policy`.
+        fn annotation_fields(
+            &mut self,
+            fields: BTreeMap<String, Value>,
+        ) -> io::Result<BTreeMap<String, Value>> {
+            let mut checked = BTreeMap::new();
+            for (name, value) in fields {
+                if RESERVED_FIELDS.contains(&name.as_str()) {
+                    continue;
+                }
+                let value = match value {
+                    Value::String(url) if url::URL_ATTRIBUTES.contains(&name.as_str()) => {
+                        match self.check_url(url.into())? {
+                            Some(url) => Value::String(url.into_string()),
+                            None => continue,
+                        }
+                    }
+                    value => value,
+                };
+                checked.insert(name, value);
+            }
+            Ok(checked)
+        }
+
         fn add_text(&mut self, text: CowStr<'a>) -> io::Result<()> {
             if let Some(last_span) = self.last_span() {
                 last_span.text += &text;
@@ -498,7 +1859,7 @@ pub mod portabletext {
         }
 
         fn add_span_with_type(&mut self, text: CowStr<'a>, _type: String) -> io::Result<()> {
-            let marks: Vec<Decorators> = self.active_markers.to_vec();
+            let marks = self.active_marks();
             if let Some(current_node) = self.last_block() {
                 current_node.children.push(SpanNode {
                     _type,
@@ -510,14 +1871,81 @@ pub mod portabletext {
             Ok(())
         }
 
+        /// The active marks, renamed as `Options::decorator_names` says.
+        fn active_marks(&self) -> Vec<Decorators> {
+            let names = &self.options.decorator_names;
+            self.active_markers
+                .iter()
+                .map(|mark| match names.get(mark) {
+                    Some(name) => Decorators::Custom(name.to_owned()),
+                    None => mark.clone(),
+                })
+                .collect()
+        }
+
+        fn code_span(&mut self, code: CowStr<'a>) -> io::Result<()> {
+            self.mark_start(Decorators::Code)?;
+            self.add_span(code)?;
+            self.mark_stop(Decorators::Code)
+        }
+
+        /// Adds an inline object with the active marks, so it stays inside
+        /// the links and emphasis around it.
+        fn push_inline_object(&mut self, object: InlineObject) -> io::Result<()> {
+            let mut child = SpanNode::object(object._type);
+            child.marks = self.active_marks();
+            if let Some(block) = self.last_block() {
+                block
+                    .child_fields
+                    .insert(block.children.len(), object.fields);
+                block.children.push(child);
+            }
+            Ok(())
+        }
+
         fn close_block(&mut self) -> io::Result<()> {
             self.open_block = false;
+            while let Some((_, key)) = self.open_annotations.pop() {
+                self.mark_stop(Decorators::AnnotationReference(key))?;
+            }
             Ok(())
         }
 
+        /// Adds a `MarkDef::Custom` to the current block and marks the text
+        /// that follows with it, returning its key.
+        fn open_annotation(
+            &mut self,
+            _type: String,
+            fields: BTreeMap<String, Value>,
+        ) -> io::Result<String> {
+            let key = random_key();
+            self.add_mark_def(MarkDef::Custom(MarkDefCustom {
+                _key: key.to_owned(),
+                _type: _type.to_owned(),
+                fields,
+            }))?;
+            self.open_annotations.push((_type, key.to_owned()));
+            self.mark_start(Decorators::AnnotationReference(key.to_owned()))?;
+            Ok(key)
+        }
+
+        /// Ends the innermost open annotation of `_type`, if there is one.
+        fn close_annotation(&mut self, _type: &str) -> io::Result<()> {
+            let index = self
+                .open_annotations
+                .iter()
+                .rposition(|(open_type, _)| open_type == _type);
+            match index {
+                Some(index) => {
+                    let (_, key) = self.open_annotations.remove(index);
+                    self.mark_stop(Decorators::AnnotationReference(key))
+                }
+                None => Ok(()),
+            }
+        }
+
         fn last_block(&mut self) -> Option<&mut BlockNode> {
-            let length = self.writer.len();
-            self.writer.get_mut(length - 1)
+            self.writer.last_mut()
         }
 
         fn last_span(&mut self) -> Option<&mut SpanNode> {
@@ -545,9 +1973,97 @@ pub mod portabletext {
         }
 
         fn mark_stop(&mut self, decorator: Decorators) -> io::Result<()> {
-            let index = self.active_markers.iter().position(|d| d == &decorator);
-            self.active_markers.remove(index.unwrap());
-            Ok(())
+            match self.active_markers.iter().position(|d| d == &decorator) {
+                Some(index) => {
+                    self.active_markers.remove(index);
+                    Ok(())
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("mark {:?} is not active", decorator),
+                )),
+            }
+        }
+    }
+
+    impl<'a, I> Build for PortabletextWriter<'a, I>
+    where
+        I: Iterator<Item = (Event<'a>, Option<Range<usize>>)>,
+    {
+        fn push_block(&mut self, block: BlockNode, open: bool) -> io::Result<()> {
+            if open {
+                self.write(block)
+            } else {
+                self.write_object(block)
+            }
+        }
+
+        fn close_block(&mut self) -> io::Result<()> {
+            PortabletextWriter::close_block(self)
+        }
+
+        fn last_block(&mut self) -> Option<&mut BlockNode> {
+            if self.writer.len() > self.first_block {
+                self.writer.last_mut()
+            } else {
+                None
+            }
+        }
+
+        fn pop_block(&mut self) -> Option<BlockNode> {
+            if self.writer.len() > self.first_block {
+                self.writer.pop()
+            } else {
+                None
+            }
+        }
+
+        fn push_span(&mut self, text: String, _type: String) -> io::Result<()> {
+            self.add_span_with_type(text.into(), _type)
+        }
+
+        fn push_inline_object(&mut self, object: InlineObject) -> io::Result<()> {
+            PortabletextWriter::push_inline_object(self, object)
+        }
+
+        fn mark_start(&mut self, decorator: Decorators) -> io::Result<()> {
+            PortabletextWriter::mark_start(self, decorator)
+        }
+
+        fn mark_stop(&mut self, decorator: Decorators) -> io::Result<()> {
+            PortabletextWriter::mark_stop(self, decorator)
+        }
+
+        fn add_mark_def(&mut self, mark_def: MarkDef) -> io::Result<()> {
+            PortabletextWriter::add_mark_def(self, mark_def)
+        }
+
+        fn open_annotation(
+            &mut self,
+            _type: String,
+            fields: BTreeMap<String, Value>,
+        ) -> io::Result<String> {
+            PortabletextWriter::open_annotation(self, _type, fields)
+        }
+
+        fn close_annotation(&mut self, _type: &str) -> io::Result<()> {
+            PortabletextWriter::close_annotation(self, _type)
+        }
+
+        fn consume_inner(&mut self) -> String {
+            PortabletextWriter::consume_inner(self)
+        }
+
+        fn warn(&mut self, kind: DiagnosticKind, message: String) {
+            self.diagnostics.push(Diagnostic {
+                kind,
+                message,
+                source_position: self.position.clone(),
+            });
+        }
+
+        fn position(&self) -> Option<Range<usize>> {
+            self.position.clone()
         }
     }
 
@@ -558,6 +2074,7 @@ pub mod portabletext {
         push_portabletext_with_opts(output, parser, Options::default())
     }
 
+    /// Panics when the conversion fails, see `try_push_portabletext_with_opts`.
     pub fn push_portabletext_with_opts<'a, I>(
         output: &'a mut Vec<BlockNode>,
         parser: I,
@@ -565,23 +2082,100 @@ pub mod portabletext {
     ) where
         I: Iterator<Item = Event<'a>>,
     {
-        PortabletextWriter::new(parser, output, options)
-            .run()
-            .unwrap();
+        try_push_portabletext_with_opts(output, parser, options).unwrap();
+    }
+
+    /// Converts `parser` into `output`, returning the diagnostics collected on
+    /// the way. Fails when an asset cannot be resolved and
+    /// `options.on_asset_error` is `AssetErrorPolicy::Error`.
+    pub fn try_push_portabletext_with_opts<'a, I>(
+        output: &'a mut Vec<BlockNode>,
+        parser: I,
+        options: Options<'a>,
+    ) -> io::Result<Vec<Diagnostic>>
+    where
+        I: Iterator<Item = Event<'a>>,
+    {
+        PortabletextWriter::new(parser.map(|event| (event, None)), output, options).run()
+    }
+
+    /// Like `try_push_portabletext_with_opts`, but for `Parser::into_offset_iter`
+    /// so resolvers and diagnostics know where in the source they are.
+    pub fn try_push_portabletext_with_offsets<'a, I>(
+        output: &'a mut Vec<BlockNode>,
+        parser: I,
+        options: Options<'a>,
+    ) -> io::Result<Vec<Diagnostic>>
+    where
+        I: Iterator<Item = (Event<'a>, Range<usize>)>,
+    {
+        let parser = parser.map(|(event, position)| (event, Some(position)));
+        PortabletextWriter::new(parser, output, options).run()
+    }
+
+    /// Parses `markdown_input` with `parser_options` and converts it like
+    /// `try_push_portabletext_with_offsets`. With `options.math` set, the
+    /// math is rewritten with `math::prepare` first, which the functions
+    /// taking a parser leave to the caller.
+    pub fn try_push_markdown(
+        output: &mut Vec<BlockNode>,
+        markdown_input: &str,
+        parser_options: pulldown_cmark::Options,
+        options: Options<'_>,
+    ) -> io::Result<Vec<Diagnostic>> {
+        let prepared;
+        let markdown_input = if options.math {
+            prepared = math::prepare(markdown_input);
+            &prepared
+        } else {
+            markdown_input
+        };
+        let parser = Parser::new_ext(markdown_input, parser_options);
+        try_push_portabletext_with_offsets(output, parser.into_offset_iter(), options)
     }
 }
 
 #[doc = include_str!("../README.md")]
-#[cfg(doctest)]
+#[cfg(all(doctest, feature = "fence_formats"))]
 pub struct ReadmeDoctests;
 
 #[cfg(test)]
+#[allow(clippy::get_first, clippy::single_component_path_imports)]
 mod tests {
     use crate::portabletext::{self, MarkDef};
+    use crate::portabletext::{
+        AssetErrorPolicy, AssetReferenceResolver, DiagnosticKind, ImageContext, Options, Picture,
+        Plugin, PluginContext, ResolveError, StyleMap, UnsafeUrlAction, UrlPolicy,
+    };
     use crate::portabletext::{BlockNode, Decorators, ListItemType, SpanNode};
-    use pulldown_cmark::Parser;
+    use pulldown_cmark::{Event, Parser, Tag};
     #[cfg(feature = "serde_serialization")]
     use serde_json;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io;
+
+    struct MissingResolver {
+        seen: RefCell<Vec<String>>,
+    }
+
+    impl AssetReferenceResolver for MissingResolver {
+        fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+            self.seen.borrow_mut().push(format!(
+                "{} {:?} {:?} {:?}",
+                context.alt,
+                context.title,
+                context.block.and_then(|b| b.style.as_ref()),
+                context.source_position
+            ));
+            Err(ResolveError::new(context.reference, "not found"))
+        }
+
+        fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+            Err(ResolveError::new(context.reference, "not found"))
+        }
+    }
+
     #[test]
     fn it_supports_heading() {
         let markdown_input = "# Hey";
@@ -622,6 +2216,242 @@ mod tests {
         assert_eq!(&second_node, portabletext_output.get(1).unwrap());
     }
 
+    #[test]
+    fn maps_styles() {
+        let markdown_input = "# Title\n\n## Section\n\n###### Deep\n\n> Quote\n\nText";
+        let styles = StyleMap {
+            normal: "paragraph".to_owned(),
+            blockquote: "quote".to_owned(),
+            headings: vec!["title".to_owned(), "h2".to_owned(), "h3".to_owned()],
+            heading_shift: 0,
+        };
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                styles,
+                ..Options::default()
+            },
+        );
+
+        let styles: Vec<&str> = portabletext_output
+            .iter()
+            .map(|block| block.style.as_deref().unwrap())
+            .collect();
+        assert_eq!(vec!["title", "h2", "h3", "quote", "paragraph"], styles);
+    }
+
+    #[test]
+    fn shifts_heading_levels() {
+        let styles = StyleMap {
+            heading_shift: 1,
+            ..StyleMap::default()
+        };
+
+        assert_eq!("h2", styles.heading(1));
+        assert_eq!("h6", styles.heading(6));
+        assert_eq!(
+            "h1",
+            StyleMap {
+                heading_shift: -1,
+                ..StyleMap::default()
+            }
+            .heading(1)
+        );
+    }
+
+    #[test]
+    fn renames_decorators() {
+        let markdown_input = "Some ~~old~~ and **new** text";
+        let mut decorator_names = HashMap::new();
+        decorator_names.insert(Decorators::Strike, "strike-through".to_owned());
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new_ext(
+                markdown_input,
+                pulldown_cmark::Options::ENABLE_STRIKETHROUGH,
+            ),
+            Options {
+                decorator_names,
+                ..Options::default()
+            },
+        );
+
+        let children = &portabletext_output[0].children;
+        assert_eq!(
+            vec![Decorators::Custom("strike-through".to_owned())],
+            children[1].marks
+        );
+        assert_eq!(vec![Decorators::Strong], children[3].marks);
+    }
+
+    #[test]
+    fn starts_a_new_block_after_code() {
+        let markdown_input = "```rust\nfn main() {}\n```\n\nAfter the code";
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext(&mut portabletext_output, Parser::new(markdown_input));
+
+        assert_eq!(2, portabletext_output.len());
+        assert!(portabletext_output[0].children.is_empty());
+        assert_eq!("After the code", portabletext_output[1].children[0].text);
+    }
+
+    /// Writes rules as `break` objects and bold text with a custom decorator.
+    struct BreaksAndBold;
+
+    impl Plugin for BreaksAndBold {
+        fn event(&self, event: &Event<'_>, context: &mut PluginContext<'_>) -> io::Result<bool> {
+            match event {
+                Event::Rule => {
+                    context.push_object(BlockNode::object("break".to_owned()))?;
+                    context.warn("rule replaced");
+                    Ok(true)
+                }
+                Event::Start(Tag::Strong) => {
+                    context.start_mark(Decorators::Custom("bold".to_owned()))?;
+                    Ok(true)
+                }
+                Event::End(Tag::Strong) => {
+                    context.stop_mark(Decorators::Custom("bold".to_owned()))?;
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        }
+    }
+
+    #[test]
+    fn plugins_handle_events_first() {
+        let markdown_input = "Some **bold** text\n\n---\n\nAfter the break";
+
+        let mut portabletext_output = vec![];
+        let diagnostics = portabletext::try_push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                plugins: vec![&BreaksAndBold],
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(3, portabletext_output.len());
+        assert_eq!(
+            vec![Decorators::Custom("bold".to_owned())],
+            portabletext_output[0].children[1].marks
+        );
+        assert_eq!("break", portabletext_output[1]._type);
+        assert_eq!("After the break", portabletext_output[2].children[0].text);
+        assert_eq!(DiagnosticKind::Plugin, diagnostics[0].kind);
+    }
+
+    #[test]
+    fn plugins_can_replace_constructs() {
+        struct Shout;
+        impl Plugin for Shout {
+            fn event(
+                &self,
+                event: &Event<'_>,
+                context: &mut PluginContext<'_>,
+            ) -> io::Result<bool> {
+                match event {
+                    Event::Start(Tag::Heading(_)) => {
+                        let text = context.consume_inner();
+                        context.push_block(BlockNode::default("normal".to_owned()))?;
+                        context.push_text(&text.to_uppercase())?;
+                        context.close_block()?;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            }
+        }
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new("# Hey *there*\n\nquiet"),
+            Options {
+                plugins: vec![&Shout],
+                ..Options::default()
+            },
+        );
+
+        assert_eq!(2, portabletext_output.len());
+        assert_eq!("HEY THERE", portabletext_output[0].children[0].text);
+        assert_eq!("quiet", portabletext_output[1].children[0].text);
+    }
+
+    #[test]
+    fn plugins_stopping_inactive_marks_fail() {
+        struct Unbalanced;
+        impl Plugin for Unbalanced {
+            fn event(
+                &self,
+                event: &Event<'_>,
+                context: &mut PluginContext<'_>,
+            ) -> io::Result<bool> {
+                match event {
+                    Event::Text(_) => {
+                        context.stop_mark(Decorators::Strong)?;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            }
+        }
+
+        let mut portabletext_output = vec![];
+        let error = portabletext::try_push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new("text"),
+            Options {
+                plugins: vec![&Unbalanced],
+                ..Options::default()
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[test]
+    fn unbalanced_links_and_lists_fail_instead_of_panicking() {
+        struct SkipStarts;
+        impl Plugin for SkipStarts {
+            fn event(
+                &self,
+                event: &Event<'_>,
+                _context: &mut PluginContext<'_>,
+            ) -> io::Result<bool> {
+                Ok(matches!(
+                    event,
+                    Event::Start(Tag::Link(..)) | Event::Start(Tag::List(_))
+                ))
+            }
+        }
+
+        for markdown_input in &["[link](https://example.com)", "- item"] {
+            let mut portabletext_output = vec![];
+            let error = portabletext::try_push_portabletext_with_opts(
+                &mut portabletext_output,
+                Parser::new(markdown_input),
+                Options {
+                    plugins: vec![&SkipStarts],
+                    ..Options::default()
+                },
+            )
+            .unwrap_err();
+
+            assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        }
+    }
+
     #[test]
     fn it_works_heading_three() {
         let markdown_input = "### Hey";
@@ -1118,6 +2948,145 @@ that is an interesting question. What for one can feel like such a no brainer, c
         };
     }
 
+    #[test]
+    fn unresolved_images_fail_by_default() {
+        let resolver = MissingResolver {
+            seen: RefCell::new(vec![]),
+        };
+        let markdown_input = "# A ![rock](/rock.jpg \"Shiprock\")";
+
+        let mut portabletext_output = vec![];
+        let result = portabletext::try_push_portabletext_with_offsets(
+            &mut portabletext_output,
+            Parser::new(markdown_input).into_offset_iter(),
+            Options {
+                asset_resolver: &resolver,
+                ..Options::default()
+            },
+        );
+
+        assert_eq!(
+            "cannot resolve /rock.jpg: not found",
+            result.unwrap_err().to_string()
+        );
+        assert_eq!(
+            vec!["rock Some(\"Shiprock\") Some(\"h1\") Some(4..33)".to_owned()],
+            resolver.seen.into_inner()
+        );
+    }
+
+    #[test]
+    fn unresolved_images_can_warn_or_drop() {
+        let resolver = MissingResolver {
+            seen: RefCell::new(vec![]),
+        };
+        let markdown_input = "Look ![rock](/rock.jpg)";
+
+        let mut warned = vec![];
+        let diagnostics = portabletext::try_push_portabletext_with_opts(
+            &mut warned,
+            Parser::new(markdown_input),
+            Options {
+                asset_resolver: &resolver,
+                on_asset_error: AssetErrorPolicy::Warn,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(DiagnosticKind::UnresolvedAsset, diagnostics[0].kind);
+        assert_eq!(1, warned[0].mark_defs.len());
+        assert_eq!("figure", warned[0].children[1]._type);
+
+        let mut dropped = vec![];
+        let diagnostics = portabletext::try_push_portabletext_with_opts(
+            &mut dropped,
+            Parser::new(markdown_input),
+            Options {
+                asset_resolver: &resolver,
+                on_asset_error: AssetErrorPolicy::Drop,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        assert!(diagnostics.is_empty());
+        assert!(dropped[0].mark_defs.is_empty());
+        assert_eq!(1, dropped[0].children.len());
+    }
+
+    #[test]
+    fn strips_unsafe_urls() {
+        let markdown_input =
+            "[ok](https://example.com) [bad](java%0ascript:x) [bad](JavaScript:alert(1)) ![x](data:image/png;base64,AA)";
+
+        let mut portabletext_output = vec![];
+        let diagnostics = portabletext::try_push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                url_policy: Some(UrlPolicy::default()),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        let block = &portabletext_output[0];
+        assert_eq!(2, block.mark_defs.len());
+        assert_eq!(2, diagnostics.len());
+        assert_eq!(DiagnosticKind::UnsafeUrl, diagnostics[0].kind);
+        assert_eq!(
+            "removed unsafe URL JavaScript:alert(1)",
+            diagnostics[0].message
+        );
+        assert!(block
+            .children
+            .iter()
+            .any(|span| span.text.contains("bad") && span.marks.is_empty()));
+    }
+
+    #[test]
+    fn rewrites_or_rejects_unsafe_urls() {
+        let markdown_input = "[bad](vbscript:x) ![x](data:image/png;base64,AA)";
+        let policy = |on_unsafe| {
+            Some(UrlPolicy {
+                on_unsafe,
+                ..UrlPolicy::default()
+            })
+        };
+
+        let mut rewritten = vec![];
+        let diagnostics = portabletext::try_push_portabletext_with_opts(
+            &mut rewritten,
+            Parser::new(markdown_input),
+            Options {
+                url_policy: policy(UnsafeUrlAction::Rewrite("#".to_owned())),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(2, diagnostics.len());
+        match &rewritten[0].mark_defs[..] {
+            [MarkDef::Link(link), MarkDef::Image(image)] => {
+                assert_eq!("#", link.href);
+                assert_eq!("#", image.src);
+            }
+            mark_defs => panic!("{:?}", mark_defs),
+        }
+
+        let result = portabletext::try_push_portabletext_with_opts(
+            &mut vec![],
+            Parser::new(markdown_input),
+            Options {
+                url_policy: policy(UnsafeUrlAction::Reject),
+                ..Options::default()
+            },
+        );
+        assert_eq!("unsafe URL vbscript:x", result.unwrap_err().to_string());
+    }
+
     #[test]
     fn inline_code() {
         let markdown_input = "A piece of inline `code snippet` marked by a backtick";
@@ -1178,5 +3147,9 @@ that is an interesting question. What for one can feel like such a no brainer, c
             "\"a23xas\"",
             serde_json::to_string(&Decorators::LinkReference("a23xas".to_owned())).unwrap()
         );
+        assert_eq!(
+            "\"highlight\"",
+            serde_json::to_string(&Decorators::Custom("highlight".to_owned())).unwrap()
+        );
     }
 }
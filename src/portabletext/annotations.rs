@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::value::Value;
+use super::{AnnotationEvent, AnnotationHandler};
+
+/// Turns inline HTML elements into custom annotations by tag name, with
+/// their attributes as string fields. With `abbr` mapped to `glossaryTerm`,
+/// `<abbr title="HyperText Markup Language">HTML</abbr>` marks `HTML` with
+/// `{ "_type": "glossaryTerm", "title": "HyperText Markup Language" }`.
+///
+/// Only inline HTML inside text opens annotations. Attributes named like
+/// `RESERVED_FIELDS`, e.g. `_type`, are dropped, and URL attributes such as
+/// `href` are checked against `Options::url_policy` like links.
+#[derive(Debug, Default, Clone)]
+pub struct HtmlAnnotations {
+    /// Annotation `_type` by lowercase tag name.
+    pub tags: HashMap<String, String>,
+}
+
+impl HtmlAnnotations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tag(mut self, tag: impl Into<String>, _type: impl Into<String>) -> Self {
+        self.tags
+            .insert(tag.into().to_ascii_lowercase(), _type.into());
+        self
+    }
+}
+
+impl AnnotationHandler for HtmlAnnotations {
+    fn inline_html(&self, html: &str) -> Option<AnnotationEvent> {
+        let tag = parse_tag(html)?;
+        let _type = self.tags.get(&tag.name)?.to_owned();
+        if tag.closing {
+            return Some(AnnotationEvent::Close { _type });
+        }
+
+        let fields = tag
+            .attributes
+            .into_iter()
+            .map(|(name, value)| (name, Value::String(value)))
+            .collect();
+        Some(AnnotationEvent::Open { _type, fields })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct HtmlTag {
+    name: String,
+    closing: bool,
+    attributes: BTreeMap<String, String>,
+}
+
+/// Parses a single opening or closing tag, like `<a href="#">` or `</a>`.
+fn parse_tag(html: &str) -> Option<HtmlTag> {
+    let inner = html.trim().strip_prefix('<')?.strip_suffix('>')?;
+    let (closing, inner) = match inner.strip_prefix('/') {
+        Some(inner) => (true, inner),
+        None => (false, inner.strip_suffix('/').unwrap_or(inner)),
+    };
+
+    let name_length = inner
+        .find(|c: char| c.is_whitespace() || c == '/')
+        .unwrap_or(inner.len());
+    let name = &inner[..name_length];
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+
+    let mut attributes = BTreeMap::new();
+    let mut rest = inner[name_length..].trim_start();
+    while !rest.is_empty() {
+        let attribute_length = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let attribute = rest[..attribute_length].to_ascii_lowercase();
+        rest = rest[attribute_length..].trim_start();
+
+        let value = match rest.strip_prefix('=') {
+            Some(value) => {
+                let value = value.trim_start();
+                let (value, remaining) = match value.chars().next() {
+                    Some(quote) if quote == '"' || quote == '\'' => {
+                        let end = value[1..].find(quote)? + 1;
+                        (&value[1..end], &value[end + 1..])
+                    }
+                    _ => {
+                        let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                        value.split_at(end)
+                    }
+                };
+                rest = remaining;
+                value.to_owned()
+            }
+            None => String::new(),
+        };
+        if !attribute.is_empty() {
+            attributes.insert(attribute, value);
+        }
+        rest = rest.trim_start();
+    }
+
+    Some(HtmlTag {
+        name: name.to_ascii_lowercase(),
+        closing,
+        attributes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, Decorators, DiagnosticKind, MarkDef, Options, UrlPolicy};
+    use pulldown_cmark::Parser;
+
+    #[test]
+    fn parses_tags() {
+        let tag = parse_tag(r#"<ABBR title="Hyper Text" lang=en hidden>"#).unwrap();
+
+        assert_eq!("abbr", tag.name);
+        assert!(!tag.closing);
+        assert_eq!(
+            vec![("hidden", ""), ("lang", "en"), ("title", "Hyper Text")],
+            tag.attributes
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert!(parse_tag("</abbr>").unwrap().closing);
+        assert_eq!(None, parse_tag("<!-- comment -->"));
+        assert_eq!(None, parse_tag("text"));
+    }
+
+    #[test]
+    #[cfg(feature = "serde_serialization")]
+    fn serializes_fields_next_to_the_type() {
+        let mut fields = BTreeMap::new();
+        fields.insert("title".to_owned(), Value::from("HTML"));
+        let mark_def = MarkDef::Custom(crate::portabletext::MarkDefCustom {
+            _key: "k".to_owned(),
+            _type: "glossaryTerm".to_owned(),
+            fields,
+        });
+
+        assert_eq!(
+            r#"{"_key":"k","_type":"glossaryTerm","title":"HTML"}"#,
+            serde_json::to_string(&mark_def).unwrap()
+        );
+    }
+
+    #[test]
+    fn annotates_inline_html() {
+        let handler = HtmlAnnotations::new().tag("abbr", "glossaryTerm");
+        let markdown_input =
+            r#"Write <abbr title="HyperText Markup Language">HTML</abbr> by <b>hand</b>"#;
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                annotation_handler: &handler,
+                ..Options::default()
+            },
+        );
+
+        let block = &portabletext_output[0];
+        let key = match &block.mark_defs[..] {
+            [MarkDef::Custom(custom)] => {
+                assert_eq!("glossaryTerm", custom._type);
+                assert_eq!(
+                    Some("HyperText Markup Language"),
+                    custom.fields["title"].as_str()
+                );
+                custom._key.to_owned()
+            }
+            mark_defs => panic!("{:?}", mark_defs),
+        };
+        let html = block.children.iter().find(|s| s.text == "HTML").unwrap();
+        assert_eq!(vec![Decorators::AnnotationReference(key)], html.marks);
+        assert!(block.children.last().unwrap().marks.is_empty());
+    }
+
+    #[test]
+    fn closes_annotations_at_the_end_of_blocks() {
+        let handler = HtmlAnnotations::new().tag("mark", "highlight");
+        let markdown_input = "<mark>never closed\n\nNext";
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                annotation_handler: &handler,
+                ..Options::default()
+            },
+        );
+
+        assert_eq!(1, portabletext_output[0].mark_defs.len());
+        assert!(portabletext_output
+            .last()
+            .unwrap()
+            .children
+            .iter()
+            .all(|span| span.marks.is_empty()));
+    }
+
+    #[test]
+    fn drops_reserved_and_unsafe_attributes() {
+        let handler = HtmlAnnotations::new().tag("cite", "source");
+        let markdown_input = r#"Said <cite _type="evil" _key=k href="javascript:alert(1)" title="A">her</cite>
+
+<cite title="block level">
+
+After"#;
+
+        let mut portabletext_output = vec![];
+        let diagnostics = portabletext::try_push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                annotation_handler: &handler,
+                url_policy: Some(UrlPolicy::default()),
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        match &portabletext_output[0].mark_defs[..] {
+            [MarkDef::Custom(custom)] => {
+                assert_eq!("source", custom._type);
+                assert_eq!(vec!["title"], custom.fields.keys().collect::<Vec<_>>());
+            }
+            mark_defs => panic!("{:?}", mark_defs),
+        }
+        assert_eq!(DiagnosticKind::UnsafeUrl, diagnostics[0].kind);
+        let after = portabletext_output.last().unwrap();
+        assert!(after.mark_defs.is_empty());
+        assert!(after.children.iter().all(|span| span.marks.is_empty()));
+    }
+}
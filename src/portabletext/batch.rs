@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+
+use super::{try_push_markdown, BlockNode, Diagnostic, Options};
+
+pub struct BatchOptions<'a> {
+    /// Glob matched against paths relative to the input directory.
+    pub pattern: String,
+    pub parser_options: pulldown_cmark::Options,
+    pub options: Options<'a>,
+    pub compact: bool,
+}
+
+impl Default for BatchOptions<'_> {
+    fn default() -> Self {
+        Self {
+            pattern: "**/*.md".to_owned(),
+            parser_options: pulldown_cmark::Options::empty(),
+            options: Options::default(),
+            compact: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BatchFailure {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+#[derive(Debug)]
+pub struct BatchDiagnostic {
+    pub path: PathBuf,
+    pub diagnostic: Diagnostic,
+}
+
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    /// Output files that were written, in input path order.
+    pub converted: Vec<PathBuf>,
+    pub failures: Vec<BatchFailure>,
+    /// Diagnostics of the converted files.
+    pub diagnostics: Vec<BatchDiagnostic>,
+}
+
+impl BatchReport {
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Converts every file below `input_dir` matching `batch_options.pattern` and
+/// writes it as `.json` to the same relative location below `output_dir`.
+///
+/// Only an unreadable `input_dir` or an invalid pattern fail the whole batch,
+/// everything else ends up in `BatchReport::failures`. That includes inputs
+/// that would overwrite each other's output, like `a.md` and `a.markdown`,
+/// none of which are converted.
+pub fn convert_dir(
+    input_dir: &Path,
+    output_dir: &Path,
+    batch_options: &BatchOptions,
+) -> io::Result<BatchReport> {
+    let pattern = Pattern::new(&batch_options.pattern)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let match_options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    if !fs::metadata(input_dir)?.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a directory", input_dir.display()),
+        ));
+    }
+
+    let mut report = BatchReport::default();
+    let mut files = vec![];
+    collect_files(input_dir, &mut files, &mut report.failures);
+    files.sort();
+
+    let mut inputs = vec![];
+    let mut inputs_by_output: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for input in files {
+        let relative = input.strip_prefix(input_dir).unwrap_or(&input);
+        if !pattern.matches_path_with(relative, match_options) {
+            continue;
+        }
+        let output = output_dir.join(relative).with_extension("json");
+        inputs_by_output
+            .entry(output.to_owned())
+            .or_default()
+            .push(input.to_owned());
+        inputs.push((input, output));
+    }
+
+    for (input, output) in inputs {
+        let clashing = &inputs_by_output[&output];
+        if clashing.len() > 1 {
+            let others: Vec<String> = clashing
+                .iter()
+                .filter(|other| **other != input)
+                .map(|other| other.display().to_string())
+                .collect();
+            let error = io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} would be written to {} as well",
+                    others.join(", "),
+                    output.display()
+                ),
+            );
+            report.failures.push(BatchFailure { path: input, error });
+            continue;
+        }
+
+        match convert_file(&input, &output, batch_options) {
+            Ok(diagnostics) => {
+                report.converted.push(output);
+                report
+                    .diagnostics
+                    .extend(diagnostics.into_iter().map(|diagnostic| BatchDiagnostic {
+                        path: input.to_owned(),
+                        diagnostic,
+                    }));
+            }
+            Err(error) => report.failures.push(BatchFailure { path: input, error }),
+        }
+    }
+
+    Ok(report)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>, failures: &mut Vec<BatchFailure>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            failures.push(BatchFailure {
+                path: dir.to_owned(),
+                error,
+            });
+            return;
+        }
+    };
+
+    for entry in entries {
+        match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))) {
+            Ok((path, file_type)) if file_type.is_dir() => collect_files(&path, files, failures),
+            Ok((path, _)) => files.push(path),
+            Err(error) => failures.push(BatchFailure {
+                path: dir.to_owned(),
+                error,
+            }),
+        }
+    }
+}
+
+fn convert_file(
+    input: &Path,
+    output: &Path,
+    batch_options: &BatchOptions,
+) -> io::Result<Vec<Diagnostic>> {
+    let markdown_input = fs::read_to_string(input)?;
+    let (blocks, diagnostics) = convert_str(&markdown_input, batch_options)?;
+
+    let json = if batch_options.compact {
+        serde_json::to_string(&blocks)
+    } else {
+        serde_json::to_string_pretty(&blocks)
+    }
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(output, json + "\n")?;
+    Ok(diagnostics)
+}
+
+fn convert_str(
+    markdown_input: &str,
+    batch_options: &BatchOptions,
+) -> io::Result<(Vec<BlockNode>, Vec<Diagnostic>)> {
+    let mut portabletext_output = vec![];
+    let diagnostics = try_push_markdown(
+        &mut portabletext_output,
+        markdown_input,
+        batch_options.parser_options,
+        batch_options.options.clone(),
+    )?;
+    Ok((portabletext_output, diagnostics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::ops::Deref;
+
+    /// A temporary directory, removed again when dropped.
+    struct ScratchDir(PathBuf);
+
+    impl Deref for ScratchDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn scratch_dir(name: &str) -> ScratchDir {
+        let dir = env::temp_dir().join(format!(
+            "pulldown_cmark_portabletext-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+
+    #[test]
+    fn mirrors_directory_structure() {
+        let input_dir = scratch_dir("batch-in");
+        let output_dir = scratch_dir("batch-out");
+        fs::create_dir_all(input_dir.join("posts/2021")).unwrap();
+        fs::write(input_dir.join("index.md"), "# Home").unwrap();
+        fs::write(input_dir.join("posts/2021/hello.md"), "Hello *world*").unwrap();
+        fs::write(input_dir.join("posts/notes.txt"), "not markdown").unwrap();
+
+        let report = convert_dir(&input_dir, &output_dir, &BatchOptions::default()).unwrap();
+
+        assert!(report.is_success());
+        assert_eq!(
+            vec![
+                output_dir.join("index.json"),
+                output_dir.join("posts/2021/hello.json")
+            ],
+            report.converted
+        );
+        let json = fs::read_to_string(output_dir.join("posts/2021/hello.json")).unwrap();
+        assert!(json.contains("\"em\""));
+        assert!(!output_dir.join("posts/notes.json").exists());
+    }
+
+    #[test]
+    fn reports_failures_without_aborting() {
+        let input_dir = scratch_dir("batch-failures-in");
+        let output_dir = scratch_dir("batch-failures-out");
+        fs::write(input_dir.join("a.md"), [0xff, 0xfe, 0x00]).unwrap();
+        fs::write(input_dir.join("b.md"), "Fine").unwrap();
+
+        let report = convert_dir(&input_dir, &output_dir, &BatchOptions::default()).unwrap();
+
+        assert_eq!(vec![output_dir.join("b.json")], report.converted);
+        assert_eq!(1, report.failures.len());
+        assert_eq!(input_dir.join("a.md"), report.failures[0].path);
+    }
+
+    #[test]
+    fn reports_inputs_with_the_same_output() {
+        let input_dir = scratch_dir("batch-clash-in");
+        let output_dir = scratch_dir("batch-clash-out");
+        fs::write(input_dir.join("a.md"), "One").unwrap();
+        fs::write(input_dir.join("a.markdown"), "Two").unwrap();
+        fs::write(input_dir.join("b.md"), "Three").unwrap();
+        let batch_options = BatchOptions {
+            pattern: "*".to_owned(),
+            ..BatchOptions::default()
+        };
+
+        let report = convert_dir(&input_dir, &output_dir, &batch_options).unwrap();
+
+        assert_eq!(vec![output_dir.join("b.json")], report.converted);
+        let failed: Vec<&Path> = report.failures.iter().map(|f| f.path.as_path()).collect();
+        assert_eq!(
+            vec![input_dir.join("a.markdown"), input_dir.join("a.md")],
+            failed
+        );
+        assert_eq!(
+            io::ErrorKind::AlreadyExists,
+            report.failures[0].error.kind()
+        );
+        assert!(!output_dir.join("a.json").exists());
+    }
+
+    #[test]
+    fn rejects_invalid_pattern() {
+        let input_dir = scratch_dir("batch-pattern");
+        let batch_options = BatchOptions {
+            pattern: "[".to_owned(),
+            ..BatchOptions::default()
+        };
+
+        assert!(convert_dir(&input_dir, &input_dir, &batch_options).is_err());
+    }
+}
@@ -0,0 +1,564 @@
+use super::plain_text::block_text;
+use super::{BlockNode, Decorators, MarkDef, SpanNode};
+
+/// Blocks without a shared `_key` are paired up when the words of their text
+/// overlap at least this much (Dice coefficient).
+const SIMILARITY_THRESHOLD: f64 = 0.5;
+
+#[derive(Debug, PartialEq)]
+pub enum BlockChange<'a> {
+    Inserted {
+        index: usize,
+        block: &'a BlockNode,
+    },
+    Removed {
+        index: usize,
+        block: &'a BlockNode,
+    },
+    /// The block changed position relative to the other blocks. It can be
+    /// modified as well, which is reported as a separate `Modified` change.
+    Moved {
+        old_index: usize,
+        new_index: usize,
+    },
+    Modified(BlockModification<'a>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BlockModification<'a> {
+    pub old_index: usize,
+    pub new_index: usize,
+    pub old: &'a BlockNode,
+    pub new: &'a BlockNode,
+    /// Names of the changed block level fields, e.g. `style` or `listItem`,
+    /// and `fields` for changed `BlockNode::fields`.
+    pub fields: Vec<&'static str>,
+    pub spans: Vec<SpanChange<'a>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SpanChange<'a> {
+    Inserted {
+        index: usize,
+        span: &'a SpanNode,
+    },
+    Removed {
+        index: usize,
+        span: &'a SpanNode,
+    },
+    Text {
+        old_index: usize,
+        new_index: usize,
+        old: &'a str,
+        new: &'a str,
+    },
+    Marks {
+        old_index: usize,
+        new_index: usize,
+        old: &'a [Decorators],
+        new: &'a [Decorators],
+    },
+    /// The fields of an inline object, see `BlockNode::child_fields`.
+    Fields {
+        old_index: usize,
+        new_index: usize,
+    },
+}
+
+/// Compares two documents block by block.
+///
+/// Blocks are paired by `_key` first (see `Options::keys`), then by identical
+/// content and finally by text similarity. Annotation marks are compared by
+/// the mark def they point to rather than by their (random) key, so
+/// re-converting the same Markdown yields no changes.
+///
+/// Removed blocks are reported first, followed by the changes in the order of
+/// `new`.
+pub fn diff<'a>(old: &'a [BlockNode], new: &'a [BlockNode]) -> Vec<BlockChange<'a>> {
+    let pairs = match_blocks(old, new);
+    let in_place = longest_increasing_run(&pairs);
+
+    let mut changes = vec![];
+    for (index, block) in old.iter().enumerate() {
+        if !pairs.iter().any(|&(old_index, _)| old_index == index) {
+            changes.push(BlockChange::Removed { index, block });
+        }
+    }
+
+    for (new_index, block) in new.iter().enumerate() {
+        let pair = pairs.iter().position(|&(_, n)| n == new_index);
+        let (pair, old_index) = match pair {
+            Some(pair) => (pair, pairs[pair].0),
+            None => {
+                changes.push(BlockChange::Inserted {
+                    index: new_index,
+                    block,
+                });
+                continue;
+            }
+        };
+
+        if !in_place.contains(&pair) {
+            changes.push(BlockChange::Moved {
+                old_index,
+                new_index,
+            });
+        }
+
+        let modification = modify_block(old_index, new_index, &old[old_index], block);
+        if !modification.fields.is_empty() || !modification.spans.is_empty() {
+            changes.push(BlockChange::Modified(modification));
+        }
+    }
+
+    changes
+}
+
+/// Pairs of `(old_index, new_index)`, sorted by old index.
+fn match_blocks(old: &[BlockNode], new: &[BlockNode]) -> Vec<(usize, usize)> {
+    let mut old_matched = vec![false; old.len()];
+    let mut new_matched = vec![false; new.len()];
+    let mut pairs = vec![];
+
+    let mut pair =
+        |o: usize, n: usize, old_matched: &mut Vec<bool>, new_matched: &mut Vec<bool>| {
+            old_matched[o] = true;
+            new_matched[n] = true;
+            pairs.push((o, n));
+        };
+
+    for (o, old_block) in old.iter().enumerate() {
+        let key = match &old_block._key {
+            Some(key) => key,
+            None => continue,
+        };
+        if let Some(n) =
+            (0..new.len()).find(|&n| !new_matched[n] && new[n]._key.as_ref() == Some(key))
+        {
+            pair(o, n, &mut old_matched, &mut new_matched);
+        }
+    }
+
+    for o in 0..old.len() {
+        if old_matched[o] {
+            continue;
+        }
+        if let Some(n) = (0..new.len()).find(|&n| !new_matched[n] && same_content(&old[o], &new[n]))
+        {
+            pair(o, n, &mut old_matched, &mut new_matched);
+        }
+    }
+
+    let mut candidates = vec![];
+    for o in (0..old.len()).filter(|&o| !old_matched[o]) {
+        for n in (0..new.len()).filter(|&n| !new_matched[n]) {
+            if old[o]._type != new[n]._type {
+                continue;
+            }
+            let score = similarity(&block_text(&old[o]), &block_text(&new[n]));
+            if score >= SIMILARITY_THRESHOLD {
+                candidates.push((score, o, n));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    for (_, o, n) in candidates {
+        if !old_matched[o] && !new_matched[n] {
+            pair(o, n, &mut old_matched, &mut new_matched);
+        }
+    }
+
+    pairs.sort_unstable();
+    pairs
+}
+
+/// Indexes into `pairs` of the longest run whose new indexes keep their
+/// relative order; every other pair has moved.
+fn longest_increasing_run(pairs: &[(usize, usize)]) -> Vec<usize> {
+    let mut lengths = vec![1; pairs.len()];
+    let mut previous: Vec<Option<usize>> = vec![None; pairs.len()];
+
+    for i in 0..pairs.len() {
+        for j in 0..i {
+            if pairs[j].1 < pairs[i].1 && lengths[j] + 1 > lengths[i] {
+                lengths[i] = lengths[j] + 1;
+                previous[i] = Some(j);
+            }
+        }
+    }
+
+    let mut run = vec![];
+    let mut current = (0..pairs.len()).max_by_key(|&i| (lengths[i], std::cmp::Reverse(i)));
+    while let Some(i) = current {
+        run.push(i);
+        current = previous[i];
+    }
+    run
+}
+
+fn modify_block<'a>(
+    old_index: usize,
+    new_index: usize,
+    old: &'a BlockNode,
+    new: &'a BlockNode,
+) -> BlockModification<'a> {
+    let mut fields = vec![];
+    if old._type != new._type {
+        fields.push("_type");
+    }
+    if old.style != new.style {
+        fields.push("style");
+    }
+    if old.list_item != new.list_item {
+        fields.push("listItem");
+    }
+    if old.level != new.level {
+        fields.push("level");
+    }
+    if old.language != new.language {
+        fields.push("language");
+    }
+    if old.code != new.code {
+        fields.push("code");
+    }
+    if !diff(&old.content, &new.content).is_empty() {
+        fields.push("content");
+    }
+    if old.fields != new.fields {
+        fields.push("fields");
+    }
+
+    BlockModification {
+        old_index,
+        new_index,
+        old,
+        new,
+        fields,
+        spans: diff_spans(old, new),
+    }
+}
+
+fn diff_spans<'a>(old: &'a BlockNode, new: &'a BlockNode) -> Vec<SpanChange<'a>> {
+    let same = |o: usize, n: usize| same_span(old, o, new, n);
+
+    // longest common subsequence of identical spans
+    let (old_len, new_len) = (old.children.len(), new.children.len());
+    let mut table = vec![vec![0usize; new_len + 1]; old_len + 1];
+    for o in (0..old_len).rev() {
+        for n in (0..new_len).rev() {
+            table[o][n] = if same(o, n) {
+                table[o + 1][n + 1] + 1
+            } else {
+                table[o + 1][n].max(table[o][n + 1])
+            };
+        }
+    }
+
+    let mut changes = vec![];
+    let (mut o, mut n) = (0, 0);
+    let (mut gap_old, mut gap_new) = (vec![], vec![]);
+    while o < old_len || n < new_len {
+        if o < old_len && n < new_len && same(o, n) {
+            changes.extend(diff_gap(old, new, &gap_old, &gap_new));
+            gap_old.clear();
+            gap_new.clear();
+            o += 1;
+            n += 1;
+        } else if n < new_len && (o == old_len || table[o][n + 1] >= table[o + 1][n]) {
+            gap_new.push(n);
+            n += 1;
+        } else {
+            gap_old.push(o);
+            o += 1;
+        }
+    }
+    changes.extend(diff_gap(old, new, &gap_old, &gap_new));
+    changes
+}
+
+/// Spans between two unchanged spans are compared position by position.
+fn diff_gap<'a>(
+    old: &'a BlockNode,
+    new: &'a BlockNode,
+    gap_old: &[usize],
+    gap_new: &[usize],
+) -> Vec<SpanChange<'a>> {
+    let mut changes = vec![];
+    for (i, &old_index) in gap_old.iter().enumerate() {
+        let old_span = &old.children[old_index];
+        let new_index = match gap_new.get(i) {
+            Some(&new_index) => new_index,
+            None => {
+                changes.push(SpanChange::Removed {
+                    index: old_index,
+                    span: old_span,
+                });
+                continue;
+            }
+        };
+        let new_span = &new.children[new_index];
+
+        if old_span.text != new_span.text || old_span._type != new_span._type {
+            changes.push(SpanChange::Text {
+                old_index,
+                new_index,
+                old: &old_span.text,
+                new: &new_span.text,
+            });
+        }
+        if !same_marks(old, old_span, new, new_span) {
+            changes.push(SpanChange::Marks {
+                old_index,
+                new_index,
+                old: &old_span.marks,
+                new: &new_span.marks,
+            });
+        }
+        if old.child_fields.get(&old_index) != new.child_fields.get(&new_index) {
+            changes.push(SpanChange::Fields {
+                old_index,
+                new_index,
+            });
+        }
+    }
+    for &index in gap_new.iter().skip(gap_old.len()) {
+        changes.push(SpanChange::Inserted {
+            index,
+            span: &new.children[index],
+        });
+    }
+    changes
+}
+
+fn same_content(old: &BlockNode, new: &BlockNode) -> bool {
+    if !same_shape(old, new) {
+        return false;
+    }
+    let modification = modify_block(0, 0, old, new);
+    modification.fields.is_empty() && modification.spans.is_empty()
+}
+
+/// Cheap checks blocks with the same content pass, so most pairs are ruled
+/// out without diffing them.
+fn same_shape(old: &BlockNode, new: &BlockNode) -> bool {
+    old._type == new._type
+        && old.style == new.style
+        && old.list_item == new.list_item
+        && old.level == new.level
+        && old.code == new.code
+        && old.fields == new.fields
+        && old.content.len() == new.content.len()
+        && old.children.len() == new.children.len()
+        && old
+            .children
+            .iter()
+            .zip(&new.children)
+            .all(|(o, n)| o._type == n._type && o.text == n.text)
+}
+
+fn same_span(old: &BlockNode, old_index: usize, new: &BlockNode, new_index: usize) -> bool {
+    let (old_span, new_span) = (&old.children[old_index], &new.children[new_index]);
+    old_span._type == new_span._type
+        && old_span.text == new_span.text
+        && same_marks(old, old_span, new, new_span)
+        && old.child_fields.get(&old_index) == new.child_fields.get(&new_index)
+}
+
+fn same_marks(old: &BlockNode, old_span: &SpanNode, new: &BlockNode, new_span: &SpanNode) -> bool {
+    old_span.marks.len() == new_span.marks.len()
+        && old_span
+            .marks
+            .iter()
+            .zip(&new_span.marks)
+            .all(|(o, n)| same_mark(old, o, new, n))
+}
+
+fn same_mark(
+    old: &BlockNode,
+    old_mark: &Decorators,
+    new: &BlockNode,
+    new_mark: &Decorators,
+) -> bool {
+    match (old_mark, new_mark) {
+        (Decorators::LinkReference(o), Decorators::LinkReference(n))
+        | (Decorators::AssetReference(o), Decorators::AssetReference(n))
+        | (Decorators::AnnotationReference(o), Decorators::AnnotationReference(n)) => {
+            match (find_mark_def(old, o), find_mark_def(new, n)) {
+                (Some(o), Some(n)) => without_key(o) == without_key(n),
+                _ => o == n,
+            }
+        }
+        _ => old_mark == new_mark,
+    }
+}
+
+fn find_mark_def<'a>(block: &'a BlockNode, key: &str) -> Option<&'a MarkDef> {
+    block
+        .mark_defs
+        .iter()
+        .find(|mark_def| mark_def.key() == key)
+}
+
+fn without_key(mark_def: &MarkDef) -> MarkDef {
+    let mut mark_def = mark_def.clone();
+    match &mut mark_def {
+        MarkDef::Link(link) => link._key.clear(),
+        MarkDef::InternalLink(link) => link._key.clear(),
+        MarkDef::Custom(custom) => custom._key.clear(),
+        MarkDef::Image(image) => image._key.clear(),
+    }
+    mark_def
+}
+
+/// Dice coefficient over the words of both texts.
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<&str> = a.split_whitespace().collect();
+    let mut b: Vec<&str> = b.split_whitespace().collect();
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+
+    let total = a.len() + b.len();
+    let mut shared = 0;
+    for word in a {
+        if let Some(position) = b.iter().position(|w| *w == word) {
+            b.swap_remove(position);
+            shared += 1;
+        }
+    }
+    (2 * shared) as f64 / total as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext;
+    use crate::portabletext::value::Value;
+    use pulldown_cmark::Parser;
+    use std::collections::BTreeMap;
+
+    fn convert(markdown_input: &str) -> Vec<BlockNode> {
+        let parser = Parser::new(markdown_input);
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext(&mut portabletext_output, parser);
+        portabletext_output
+    }
+
+    #[test]
+    fn unchanged_documents_have_no_changes() {
+        let markdown_input = "# Title\n\nSome [link](https://example.com) text.";
+
+        assert_eq!(
+            Vec::<BlockChange>::new(),
+            diff(&convert(markdown_input), &convert(markdown_input))
+        );
+    }
+
+    #[test]
+    fn reports_inserted_removed_and_moved_blocks() {
+        let old = convert("First paragraph.\n\nSecond paragraph.\n\nThird paragraph.");
+        let new = convert("Third paragraph.\n\nFirst paragraph.\n\nA brand new one.");
+
+        assert_eq!(
+            vec![
+                BlockChange::Removed {
+                    index: 1,
+                    block: &old[1]
+                },
+                BlockChange::Moved {
+                    old_index: 2,
+                    new_index: 0
+                },
+                BlockChange::Inserted {
+                    index: 2,
+                    block: &new[2]
+                },
+            ],
+            diff(&old, &new)
+        );
+    }
+
+    #[test]
+    fn reports_span_text_and_mark_changes() {
+        let old = convert("# Hello *big* world and more");
+        let new = convert("## Hello **big** world and less");
+
+        let changes = diff(&old, &new);
+        let modification = match changes.as_slice() {
+            [BlockChange::Modified(modification)] => modification,
+            _ => panic!("{:?}", changes),
+        };
+
+        assert_eq!(vec!["style"], modification.fields);
+        assert_eq!(
+            vec![
+                SpanChange::Marks {
+                    old_index: 1,
+                    new_index: 1,
+                    old: &[Decorators::Emphasis],
+                    new: &[Decorators::Strong],
+                },
+                SpanChange::Text {
+                    old_index: 2,
+                    new_index: 2,
+                    old: " world and more",
+                    new: " world and less",
+                },
+            ],
+            modification.spans
+        );
+    }
+
+    #[test]
+    fn reports_changed_inline_object_fields() {
+        let old = convert("Some text");
+        let mut new = convert("Some text");
+        new[0].children.push(SpanNode::object("math".to_owned()));
+        new[0].child_fields.insert(1, BTreeMap::new());
+        let mut changed = new.clone();
+        changed[0]
+            .child_fields
+            .get_mut(&1)
+            .unwrap()
+            .insert("tex".to_owned(), Value::from("y"));
+
+        assert_eq!(1, diff(&old, &new).len());
+        let changes = diff(&new, &changed);
+        match changes.as_slice() {
+            [BlockChange::Modified(modification)] => assert_eq!(
+                vec![SpanChange::Fields {
+                    old_index: 1,
+                    new_index: 1
+                }],
+                modification.spans
+            ),
+            _ => panic!("{:?}", changes),
+        }
+    }
+
+    #[test]
+    fn matches_by_key_first() {
+        let old = vec![
+            BlockNode::default("normal".to_owned()).with_key("a".to_owned()),
+            BlockNode::default("normal".to_owned()).with_key("b".to_owned()),
+        ];
+        let new = vec![BlockNode::default("h1".to_owned()).with_key("b".to_owned())];
+
+        let changes = diff(&old, &new);
+
+        assert_eq!(2, changes.len());
+        assert_eq!(
+            BlockChange::Removed {
+                index: 0,
+                block: &old[0]
+            },
+            changes[0]
+        );
+        match &changes[1] {
+            BlockChange::Modified(modification) => {
+                assert_eq!((1, 0), (modification.old_index, modification.new_index))
+            }
+            change => panic!("{:?}", change),
+        }
+    }
+}
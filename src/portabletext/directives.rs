@@ -0,0 +1,525 @@
+//! remark-directive style syntax: `:::name` containers around blocks,
+//! `::name` leaf directives on their own line and `:name` text directives,
+//! each with an optional `[label]` and `{attributes}`.
+
+use std::collections::BTreeMap;
+
+use super::value::Value;
+use super::{BlockNode, Directive, DirectiveHandler, DirectiveKind, InlineObject, RESERVED_FIELDS};
+
+/// GitHub alert types, as in `> [!NOTE]`.
+const ALERT_TONES: &[&str] = &["note", "tip", "important", "warning", "caution"];
+
+/// Container names that are callouts, as in `:::tip`.
+const CALLOUT_TONES: &[&str] = &[
+    "note",
+    "tip",
+    "info",
+    "important",
+    "warning",
+    "caution",
+    "danger",
+];
+
+/// Writes directives as objects of one `_type`, with the attributes as string
+/// fields and the label as `label`. Directives with an attribute named like
+/// one of the `RESERVED_FIELDS`, e.g. `{style=wide}`, are kept as text.
+///
+/// `::youtube[Intro]{v=dQw4w9WgXcQ}` mapped to `video` turns into
+/// `{ "_type": "video", "label": "Intro", "v": "dQw4w9WgXcQ" }`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectDirective {
+    pub _type: String,
+}
+
+impl ObjectDirective {
+    pub fn new(_type: impl Into<String>) -> Self {
+        Self {
+            _type: _type.into(),
+        }
+    }
+
+    /// `None`, keeping the directive as text, if an attribute is one of the
+    /// `RESERVED_FIELDS`.
+    fn fields(&self, directive: &Directive) -> Option<BTreeMap<String, Value>> {
+        if directive
+            .attributes
+            .keys()
+            .any(|name| RESERVED_FIELDS.contains(&name.as_str()))
+        {
+            return None;
+        }
+        let mut fields: BTreeMap<String, Value> = directive
+            .attributes
+            .iter()
+            .map(|(name, value)| (name.to_owned(), Value::from(value.as_str())))
+            .collect();
+        if let Some(label) = &directive.label {
+            fields.insert("label".to_owned(), Value::from(label.as_str()));
+        }
+        Some(fields)
+    }
+}
+
+impl DirectiveHandler for ObjectDirective {
+    fn block(&self, directive: &Directive) -> Option<BlockNode> {
+        let mut block = BlockNode::object(self._type.to_owned());
+        block.fields = self.fields(directive)?;
+        Some(block)
+    }
+
+    fn inline(&self, directive: &Directive) -> Option<InlineObject> {
+        Some(InlineObject {
+            _type: self._type.to_owned(),
+            fields: self.fields(directive)?,
+        })
+    }
+}
+
+/// Turns `:::tip` and the other tone containers into callouts, see
+/// `Options::callouts`.
+pub(crate) struct Callouts;
+
+impl DirectiveHandler for Callouts {
+    fn block(&self, directive: &Directive) -> Option<BlockNode> {
+        if directive.kind != DirectiveKind::Container {
+            return None;
+        }
+        let tone = directive.name.to_ascii_lowercase();
+        if !CALLOUT_TONES.contains(&tone.as_str()) {
+            return None;
+        }
+
+        let mut block = callout(tone);
+        if let Some(label) = &directive.label {
+            block
+                .fields
+                .insert("title".to_owned(), Value::from(label.as_str()));
+        }
+        Some(block)
+    }
+}
+
+pub(crate) fn callout(tone: String) -> BlockNode {
+    let mut block = BlockNode::object("callout".to_owned());
+    block.fields.insert("tone".to_owned(), Value::from(tone));
+    block
+}
+
+/// A line of only colons and a directive, as in `:::tip[Title]`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ContainerFence {
+    Open(Directive),
+    Close,
+}
+
+/// Parses a line opening or closing a container, which takes at least three
+/// colons. Besides `[Title]`, the label may follow after a space, as in
+/// `:::tip Title`.
+pub(crate) fn parse_container_fence(line: &str) -> Option<ContainerFence> {
+    let line = line.trim();
+    let rest = line.trim_start_matches(':');
+    if line.len() - rest.len() < 3 {
+        return None;
+    }
+    if rest.is_empty() {
+        return Some(ContainerFence::Close);
+    }
+
+    let (mut directive, rest) = parse_directive(DirectiveKind::Container, rest)?;
+    if rest.starts_with(' ') && directive.label.is_none() {
+        directive.label = Some(rest.trim().to_owned());
+    } else if !rest.trim().is_empty() {
+        return None;
+    }
+    Some(ContainerFence::Open(directive))
+}
+
+/// Parses a line with only a leaf directive, like `::youtube{v=123}`.
+pub(crate) fn parse_leaf(line: &str) -> Option<Directive> {
+    let rest = line.trim().strip_prefix("::")?;
+    if rest.starts_with(':') {
+        return None;
+    }
+    match parse_directive(DirectiveKind::Leaf, rest)? {
+        (directive, "") => Some(directive),
+        _ => None,
+    }
+}
+
+/// Finds the first text directive, like `:abbr[HTML]{title="…"}`, returning
+/// its byte range in `text`. Text directives need a label or attributes, and
+/// may not follow a letter, digit or colon, so `10:30` and `https://` are
+/// left alone.
+pub(crate) fn find_text_directive(text: &str) -> Option<(usize, usize, Directive)> {
+    let mut previous = ' ';
+    for (start, c) in text.char_indices() {
+        if c == ':' && !(previous.is_alphanumeric() || previous == ':') {
+            let rest = &text[start + 1..];
+            if let Some((directive, remainder)) = parse_directive(DirectiveKind::Text, rest) {
+                if directive.label.is_some() || !directive.attributes.is_empty() {
+                    return Some((start, text.len() - remainder.len(), directive));
+                }
+            }
+        }
+        previous = c;
+    }
+    None
+}
+
+/// Parses `name[label]{attributes}`, returning the directive and the rest.
+fn parse_directive(kind: DirectiveKind, text: &str) -> Option<(Directive, &str)> {
+    let name_length = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .unwrap_or(text.len());
+    if name_length == 0 || !text.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let (name, mut rest) = text.split_at(name_length);
+
+    let mut label = None;
+    if rest.starts_with('[') {
+        let mut depth = 0;
+        let end = rest.char_indices().find_map(|(index, c)| {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                _ => {}
+            }
+            (depth == 0).then_some(index)
+        })?;
+        label = Some(rest[1..end].to_owned());
+        rest = &rest[end + 1..];
+    }
+
+    let mut attributes = BTreeMap::new();
+    if let Some(inner) = rest.strip_prefix('{') {
+        let end = inner.find('}')?;
+        attributes = parse_attributes(&inner[..end])?;
+        rest = &inner[end + 1..];
+    }
+
+    let directive = Directive {
+        kind,
+        name: name.to_owned(),
+        label,
+        attributes,
+    };
+    Some((directive, rest))
+}
+
+/// Parses `#id .class key=value key="quoted value"` attributes. Classes are
+/// joined into `class`.
+fn parse_attributes(text: &str) -> Option<BTreeMap<String, String>> {
+    let mut attributes = BTreeMap::new();
+    let mut classes: Vec<&str> = vec![];
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        let length = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let (name, remaining) = rest.split_at(length);
+        if name.is_empty() {
+            return None;
+        }
+        rest = remaining.trim_start();
+
+        if let Some(id) = name.strip_prefix('#') {
+            attributes.insert("id".to_owned(), id.to_owned());
+        } else if let Some(class) = name.strip_prefix('.') {
+            classes.push(class);
+        } else if let Some(value) = rest.strip_prefix('=') {
+            let value = value.trim_start();
+            let (value, remaining) = match value.chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => {
+                    let end = value[1..].find(quote)? + 1;
+                    (&value[1..end], &value[end + 1..])
+                }
+                _ => value.split_at(value.find(char::is_whitespace).unwrap_or(value.len())),
+            };
+            attributes.insert(name.to_owned(), value.to_owned());
+            rest = remaining;
+        } else {
+            attributes.insert(name.to_owned(), String::new());
+        }
+        rest = rest.trim_start();
+    }
+
+    if !classes.is_empty() {
+        attributes.insert("class".to_owned(), classes.join(" "));
+    }
+    Some(attributes)
+}
+
+/// The tone of a quote starting with a GitHub alert line like `[!WARNING]`.
+pub(crate) fn alert_tone(line: &str) -> Option<String> {
+    let tone = line
+        .trim()
+        .strip_prefix("[!")?
+        .strip_suffix(']')?
+        .to_ascii_lowercase();
+    ALERT_TONES.contains(&tone.as_str()).then_some(tone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, Options};
+    use pulldown_cmark::Parser;
+    use std::collections::HashMap;
+
+    fn convert(markdown_input: &str) -> Vec<BlockNode> {
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                callouts: true,
+                ..Options::default()
+            },
+        );
+        portabletext_output
+    }
+
+    fn texts(blocks: &[BlockNode]) -> Vec<String> {
+        blocks
+            .iter()
+            .map(portabletext::plain_text::block_text)
+            .collect()
+    }
+
+    fn convert_with(
+        markdown_input: &str,
+        directive: &str,
+        handler: &ObjectDirective,
+    ) -> Vec<BlockNode> {
+        let mut directive_handlers: HashMap<String, &dyn DirectiveHandler> = HashMap::new();
+        directive_handlers.insert(directive.to_owned(), handler);
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                directive_handlers,
+                ..Options::default()
+            },
+        );
+        portabletext_output
+    }
+
+    fn directive(kind: DirectiveKind, name: &str, label: Option<&str>) -> Directive {
+        Directive {
+            kind,
+            name: name.to_owned(),
+            label: label.map(str::to_owned),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn parses_directives() {
+        let tip = directive(DirectiveKind::Container, "tip", None);
+        assert_eq!(
+            Some(ContainerFence::Open(tip)),
+            parse_container_fence(":::tip")
+        );
+        let mut note = directive(DirectiveKind::Container, "note", Some("Read [this]"));
+        note.attributes.insert("id".to_owned(), "a".to_owned());
+        note.attributes.insert("class".to_owned(), "b c".to_owned());
+        note.attributes.insert("title".to_owned(), "x y".to_owned());
+        note.attributes.insert("open".to_owned(), "".to_owned());
+        assert_eq!(
+            Some(ContainerFence::Open(note)),
+            parse_container_fence(r#"::::note[Read [this]]{#a .b title="x y" .c open}"#)
+        );
+        assert_eq!(
+            Some(ContainerFence::Open(directive(
+                DirectiveKind::Container,
+                "warning",
+                Some("Careful now")
+            ))),
+            parse_container_fence(":::warning Careful now")
+        );
+        assert_eq!(Some(ContainerFence::Close), parse_container_fence(":::"));
+        assert_eq!(None, parse_container_fence("::tip"));
+        assert_eq!(None, parse_container_fence(":::[x]"));
+
+        assert_eq!(
+            Some(directive(DirectiveKind::Leaf, "toc", None)),
+            parse_leaf("::toc")
+        );
+        assert_eq!(None, parse_leaf("::toc and more"));
+        assert_eq!(None, parse_leaf(":::toc"));
+
+        let (start, end, abbr) = find_text_directive("At 10:30 :abbr[HTML]{lang=en}!").unwrap();
+        assert_eq!((9, 29), (start, end));
+        assert_eq!(Some("HTML".to_owned()), abbr.label);
+        assert_eq!(
+            None,
+            find_text_directive("See https://example.com or :plain")
+        );
+
+        assert_eq!(Some("warning".to_owned()), alert_tone("[!WARNING]"));
+        assert_eq!(None, alert_tone("[!link]"));
+    }
+
+    #[test]
+    fn routes_directives_to_handlers() {
+        let video = ObjectDirective::new("video");
+        let glossary = ObjectDirective::new("glossaryTerm");
+        let aside = ObjectDirective::new("aside");
+        let mut directive_handlers: HashMap<String, &dyn DirectiveHandler> = HashMap::new();
+        directive_handlers.insert("youtube".to_owned(), &video);
+        directive_handlers.insert("abbr".to_owned(), &glossary);
+        directive_handlers.insert("note".to_owned(), &aside);
+        let markdown_input = "::youtube[Intro]{v=123}\n\nWrite :abbr[HTML]{title=\"Hyper Text\"} by hand.\n\n:::note{.wide}\nInside\n:::\n\n::unknown{a=b}";
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                directive_handlers,
+                ..Options::default()
+            },
+        );
+
+        let types: Vec<&str> = portabletext_output
+            .iter()
+            .map(|block| block._type.as_str())
+            .collect();
+        assert_eq!(vec!["video", "block", "aside", "block"], types);
+        assert_eq!(
+            Some(&Value::from("123")),
+            portabletext_output[0].fields.get("v")
+        );
+        assert_eq!(
+            Some(&Value::from("Intro")),
+            portabletext_output[0].fields.get("label")
+        );
+
+        let children = &portabletext_output[1].children;
+        assert_eq!(3, children.len());
+        assert_eq!("glossaryTerm", children[1]._type);
+        assert_eq!(
+            Some(&Value::from("Hyper Text")),
+            portabletext_output[1].child_fields[&1].get("title")
+        );
+        assert_eq!(" by hand.", children[2].text);
+
+        let aside = &portabletext_output[2];
+        assert_eq!(Some(&Value::from("wide")), aside.fields.get("class"));
+        assert_eq!(vec!["Inside"], texts(&aside.content));
+        assert_eq!(vec!["::unknown{a=b}"], texts(&portabletext_output[3..]));
+    }
+
+    #[test]
+    fn keeps_directives_with_reserved_attributes_as_text() {
+        let blocks = convert_with(
+            "::youtube{text=a}\n\nSee :youtube{style=wide}.",
+            "youtube",
+            &ObjectDirective::new("video"),
+        );
+
+        assert_eq!(
+            vec!["::youtube{text=a}", "See :youtube{style=wide}."],
+            texts(&blocks)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde_serialization")]
+    fn serializes_inline_objects_without_text() {
+        let blocks = convert_with(
+            "Write :abbr[HTML]{title=\"Hyper Text\"}.",
+            "abbr",
+            &ObjectDirective::new("glossaryTerm"),
+        );
+
+        let json = serde_json::to_string(&blocks[0]).unwrap();
+        assert!(json.contains(r#"{"_type":"glossaryTerm","label":"HTML","title":"Hyper Text"}"#));
+    }
+
+    #[test]
+    fn turns_alert_quotes_into_callouts() {
+        let blocks = convert("> [!NOTE]\n> Read *this*.\n>\n> And this.\n\n> Just a quote");
+
+        assert_eq!(2, blocks.len());
+        let callout = &blocks[0];
+        assert_eq!("callout", callout._type);
+        assert_eq!(Some(&Value::from("note")), callout.fields.get("tone"));
+        assert_eq!(vec!["Read this.", "And this."], texts(&callout.content));
+        assert_eq!(Some("normal".to_owned()), callout.content[0].style);
+        assert_eq!(Some("blockquote".to_owned()), blocks[1].style);
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext(&mut portabletext_output, Parser::new("> [!NOTE]\n> Kept"));
+        assert_eq!(vec!["[!NOTE] Kept"], texts(&portabletext_output));
+    }
+
+    #[test]
+    fn turns_containers_into_callouts() {
+        let blocks = convert(
+            ":::tip[Shortcut]\nPress **Ctrl**.\n:::\n\n:::warning\n\n- one\n- two\n\n:::\n\n:::unknown\nText\n:::",
+        );
+
+        let types: Vec<&str> = blocks.iter().map(|block| block._type.as_str()).collect();
+        assert_eq!(vec!["callout", "callout", "block"], types);
+        assert_eq!(Some(&Value::from("tip")), blocks[0].fields.get("tone"));
+        assert_eq!(
+            Some(&Value::from("Shortcut")),
+            blocks[0].fields.get("title")
+        );
+        assert_eq!(vec!["Press Ctrl."], texts(&blocks[0].content));
+        assert_eq!(vec!["one", "two"], texts(&blocks[1].content));
+        assert!(blocks[1].content[0].list_item.is_some());
+        assert_eq!(vec![":::unknown Text :::"], texts(&blocks[2..]));
+    }
+
+    #[test]
+    fn closes_containers_right_after_tight_lists() {
+        let blocks = convert(":::warning\n- one\n- two\n:::\n\nAfter");
+
+        assert_eq!(2, blocks.len());
+        assert_eq!("callout", blocks[0]._type);
+        assert_eq!(vec!["one", "two"], texts(&blocks[0].content));
+        assert!(blocks[0].content[1].list_item.is_some());
+        assert_eq!(vec!["After"], texts(&blocks[1..]));
+    }
+
+    #[test]
+    fn closes_alert_quotes_left_open() {
+        let blocks = convert("> [!TIP]\n> > Nested\n\nAfter");
+
+        assert_eq!(2, blocks.len());
+        assert_eq!(vec!["Nested"], texts(&blocks[0].content));
+        assert_eq!("block", blocks[1]._type);
+    }
+
+    #[test]
+    fn keeps_unclosed_containers_as_paragraphs() {
+        let blocks = convert(":::caution\nUnclosed\n\nMore *text*\n\n:::tip[Inner]\nDone\n:::");
+
+        let types: Vec<&str> = blocks.iter().map(|block| block._type.as_str()).collect();
+        assert_eq!(vec!["block", "block", "callout"], types);
+        assert_eq!(
+            vec![":::caution Unclosed", "More text"],
+            texts(&blocks[..2])
+        );
+        assert_eq!(vec!["Done"], texts(&blocks[2].content));
+
+        let blocks = convert(":::note\n\n- one\n- two");
+        assert_eq!(vec![":::note", "one", "two"], texts(&blocks));
+    }
+
+    #[test]
+    fn ends_unclosed_containers_with_their_quote() {
+        let blocks = convert("> [!NOTE]\n> :::caution\n> Inside\n\nAfter");
+
+        assert_eq!(2, blocks.len());
+        assert_eq!("callout", blocks[0]._type);
+        assert_eq!(vec![":::caution Inside"], texts(&blocks[0].content));
+        assert_eq!(vec!["After"], texts(&blocks[1..]));
+    }
+}
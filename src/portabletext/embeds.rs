@@ -0,0 +1,223 @@
+use regex::Regex;
+
+use super::url;
+use super::value::Value;
+use super::{BlockNode, Decorators, MarkDef};
+
+/// A site whose URLs are embedded, like YouTube. The `pattern` matches the
+/// whole URL; the id is its `id` group, or else its first group.
+#[derive(Debug, Clone)]
+pub struct EmbedProvider {
+    pub name: String,
+    pub pattern: Regex,
+}
+
+/// Turns paragraphs of only a URL into `embed` objects with the `provider`,
+/// `id` and `url`, see `Options::embeds`. URLs are matched against the
+/// providers in order, without requesting anything.
+#[derive(Debug, Default, Clone)]
+pub struct EmbedProviders {
+    pub providers: Vec<EmbedProvider>,
+}
+
+impl EmbedProviders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// YouTube, Vimeo, Twitter/X and CodePen.
+    pub fn builtin() -> Self {
+        let builtin = [
+            (
+                "youtube",
+                r"^https?://(?:www\.|m\.)?(?:youtube\.com/(?:watch\?(?:[^#]*&)?v=|embed/|shorts/|live/)|youtu\.be/)(?P<id>[A-Za-z0-9_-]{11})(?:[?&#/].*)?$",
+            ),
+            (
+                "vimeo",
+                r"^https?://(?:www\.|player\.)?vimeo\.com/(?:video/)?(?P<id>\d+)(?:[/?#].*)?$",
+            ),
+            (
+                "twitter",
+                r"^https?://(?:www\.|mobile\.)?(?:twitter|x)\.com/\w+/status(?:es)?/(?P<id>\d+)(?:[/?#].*)?$",
+            ),
+            (
+                "codepen",
+                r"^https?://codepen\.io/[^/?#]+/(?:pen|embed|full|details)/(?P<id>\w+)(?:[/?#].*)?$",
+            ),
+        ];
+        builtin
+            .iter()
+            .fold(Self::new(), |providers, (name, pattern)| {
+                providers.provider(*name, Regex::new(pattern).unwrap())
+            })
+    }
+
+    pub fn provider(mut self, name: impl Into<String>, pattern: Regex) -> Self {
+        self.providers.push(EmbedProvider {
+            name: name.into(),
+            pattern,
+        });
+        self
+    }
+
+    /// The `embed` object for `url`, if a provider matches it.
+    pub fn embed(&self, url: &str) -> Option<BlockNode> {
+        self.providers.iter().find_map(|provider| {
+            let captures = provider.pattern.captures(url)?;
+            let id = captures.name("id").or_else(|| captures.get(1))?;
+
+            let mut block = BlockNode::object("embed".to_owned());
+            block
+                .fields
+                .insert("provider".to_owned(), Value::from(provider.name.as_str()));
+            block
+                .fields
+                .insert("id".to_owned(), Value::from(id.as_str()));
+            block.fields.insert("url".to_owned(), Value::from(url));
+            Some(block)
+        })
+    }
+}
+
+/// The URL a block consists of, written as text or as a link to itself.
+pub(crate) fn standalone_url(block: &BlockNode) -> Option<String> {
+    let text: String = block
+        .children
+        .iter()
+        .map(|span| span.text.as_str())
+        .collect();
+    let url = text.trim();
+    let web = matches!(url::scheme(url).as_deref(), Some("http") | Some("https"));
+    if !web || url.contains(char::is_whitespace) {
+        return None;
+    }
+
+    for span in &block.children {
+        if span._type != "span" {
+            return None;
+        }
+        for mark in &span.marks {
+            let key = match mark {
+                Decorators::LinkReference(key) => key,
+                _ => return None,
+            };
+            match block
+                .mark_defs
+                .iter()
+                .find(|mark_def| mark_def.key() == key)
+            {
+                Some(MarkDef::Link(link)) if link.href == url => {}
+                _ => return None,
+            }
+        }
+    }
+    Some(url.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, Options};
+    use pulldown_cmark::Parser;
+
+    fn convert(markdown_input: &str, embeds: EmbedProviders) -> Vec<BlockNode> {
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                callouts: true,
+                embeds: Some(embeds),
+                ..Options::default()
+            },
+        );
+        portabletext_output
+    }
+
+    fn field<'b>(block: &'b BlockNode, name: &str) -> Option<&'b str> {
+        block.fields.get(name).and_then(Value::as_str)
+    }
+
+    #[test]
+    fn matches_builtin_providers() {
+        let providers = EmbedProviders::builtin();
+        let embed = |url: &str| {
+            providers.embed(url).map(|block| {
+                (
+                    field(&block, "provider").unwrap().to_owned(),
+                    field(&block, "id").unwrap().to_owned(),
+                )
+            })
+        };
+        let expect = |provider: &str, id: &str| Some((provider.to_owned(), id.to_owned()));
+
+        assert_eq!(
+            expect("youtube", "dQw4w9WgXcQ"),
+            embed("https://www.youtube.com/watch?t=10&v=dQw4w9WgXcQ")
+        );
+        assert_eq!(
+            expect("youtube", "dQw4w9WgXcQ"),
+            embed("https://youtu.be/dQw4w9WgXcQ?t=42")
+        );
+        assert_eq!(
+            expect("vimeo", "76979871"),
+            embed("https://vimeo.com/76979871")
+        );
+        assert_eq!(
+            expect("twitter", "1460323737035677698"),
+            embed("https://x.com/rustlang/status/1460323737035677698")
+        );
+        assert_eq!(
+            expect("codepen", "abcXYZ"),
+            embed("https://codepen.io/someone/pen/abcXYZ")
+        );
+        assert_eq!(None, embed("https://www.youtube.com/@rustlang"));
+        assert_eq!(None, embed("https://example.com/youtu.be/dQw4w9WgXcQ"));
+    }
+
+    #[test]
+    fn embeds_paragraphs_of_only_a_url() {
+        let blocks = convert(
+            "https://youtu.be/dQw4w9WgXcQ\n\n<https://vimeo.com/76979871>\n\nWatch https://vimeo.com/1\n\n[Video](https://vimeo.com/2)\n\n- https://vimeo.com/3",
+            EmbedProviders::builtin(),
+        );
+
+        let types: Vec<&str> = blocks.iter().map(|block| block._type.as_str()).collect();
+        assert_eq!(vec!["embed", "embed", "block", "block", "block"], types);
+        assert_eq!(
+            Some("https://youtu.be/dQw4w9WgXcQ"),
+            field(&blocks[0], "url")
+        );
+        assert_eq!(Some("76979871"), field(&blocks[1], "id"));
+    }
+
+    #[test]
+    fn embeds_in_callouts_but_not_in_quotes() {
+        let blocks = convert(
+            "> [!NOTE]\n> https://youtu.be/dQw4w9WgXcQ\n\n:::tip\nhttps://vimeo.com/76979871\n:::\n\n> https://vimeo.com/1",
+            EmbedProviders::builtin(),
+        );
+
+        let types: Vec<&str> = blocks.iter().map(|block| block._type.as_str()).collect();
+        assert_eq!(vec!["callout", "callout", "block"], types);
+        assert_eq!("embed", blocks[0].content[0]._type);
+        assert_eq!("embed", blocks[1].content[0]._type);
+    }
+
+    #[test]
+    fn takes_custom_providers() {
+        let providers = EmbedProviders::new().provider(
+            "loom",
+            Regex::new(r"^https://www\.loom\.com/share/([0-9a-f]+)$").unwrap(),
+        );
+
+        let blocks = convert(
+            "https://www.loom.com/share/0123abcd\n\nhttps://youtu.be/dQw4w9WgXcQ",
+            providers,
+        );
+
+        assert_eq!(Some("loom"), field(&blocks[0], "provider"));
+        assert_eq!(Some("0123abcd"), field(&blocks[0], "id"));
+        assert_eq!("block", blocks[1]._type);
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+
+use super::value::Value;
+use super::{BlockNode, FenceFormat, FenceHandler, RESERVED_FIELDS};
+
+/// Parses `code` as `handler` asks for and builds its block.
+pub(crate) fn fence_block(
+    handler: &dyn FenceHandler,
+    info: &str,
+    code: &str,
+) -> Result<BlockNode, String> {
+    let content = parse(handler.format(), code)?;
+    handler.block(info, content)
+}
+
+#[cfg(feature = "fence_formats")]
+fn parse(format: FenceFormat, code: &str) -> Result<Value, String> {
+    match format {
+        FenceFormat::Raw => Ok(Value::from(code)),
+        FenceFormat::Json => serde_json::from_str::<serde_json::Value>(code)
+            .map(Value::from)
+            .map_err(|e| e.to_string()),
+        FenceFormat::Yaml => serde_yaml::from_str::<serde_json::Value>(code)
+            .map(Value::from)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+#[cfg(not(feature = "fence_formats"))]
+fn parse(format: FenceFormat, code: &str) -> Result<Value, String> {
+    match format {
+        FenceFormat::Raw => Ok(Value::from(code)),
+        FenceFormat::Json | FenceFormat::Yaml => {
+            Err("parsing JSON and YAML requires the `fence_formats` feature".to_owned())
+        }
+    }
+}
+
+/// Writes fenced blocks as objects of one `_type`. The fields of an object
+/// become fields of the block; any other content, like raw text or a list, is
+/// written to `value`. Objects with one of the `RESERVED_FIELDS`, like
+/// `_type`, are rejected. With `chart` mapped to a YAML `chart` object,
+///
+/// ````markdown
+/// ```chart
+/// kind: bar
+/// values: [1, 2]
+/// ```
+/// ````
+///
+/// turns into `{ "_type": "chart", "kind": "bar", "values": [1, 2] }`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectFence {
+    pub _type: String,
+    pub format: FenceFormat,
+}
+
+impl ObjectFence {
+    pub fn new(_type: impl Into<String>, format: FenceFormat) -> Self {
+        Self {
+            _type: _type.into(),
+            format,
+        }
+    }
+}
+
+impl FenceHandler for ObjectFence {
+    fn format(&self) -> FenceFormat {
+        self.format
+    }
+
+    fn block(&self, _info: &str, content: Value) -> Result<BlockNode, String> {
+        let mut block = BlockNode::object(self._type.to_owned());
+        match content {
+            Value::Object(fields) => {
+                if let Some(reserved) = fields
+                    .keys()
+                    .find(|key| RESERVED_FIELDS.contains(&key.as_str()))
+                {
+                    return Err(format!("`{}` is a reserved field", reserved));
+                }
+                block.fields = fields;
+            }
+            content => {
+                block.fields.insert("value".to_owned(), content);
+            }
+        }
+        Ok(block)
+    }
+}
+
+/// The languages `DiagramFence` is meant for.
+pub const DIAGRAM_LANGUAGES: &[&str] = &["mermaid", "plantuml", "graphviz", "dot"];
+
+/// Writes diagram sources, like ```` ```mermaid ````, as `diagram` objects with
+/// the `engine`, the fence language, and the `source`. `dot` is written as the
+/// `graphviz` engine.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DiagramFence;
+
+impl FenceHandler for DiagramFence {
+    fn format(&self) -> FenceFormat {
+        FenceFormat::Raw
+    }
+
+    fn block(&self, info: &str, content: Value) -> Result<BlockNode, String> {
+        let engine = match info.split_whitespace().next() {
+            Some("dot") => "graphviz",
+            Some(language) => language,
+            None => return Err("no diagram language".to_owned()),
+        };
+        let source = content.as_str().unwrap_or_default().trim_end_matches('\n');
+
+        let mut block = BlockNode::object("diagram".to_owned());
+        block.fields.insert(
+            "engine".to_owned(),
+            Value::from(engine.to_ascii_lowercase()),
+        );
+        block
+            .fields
+            .insert("source".to_owned(), Value::from(source));
+        Ok(block)
+    }
+}
+
+/// A `DiagramFence` for each of `DIAGRAM_LANGUAGES`, to use as or add to
+/// `Options::fence_handlers`.
+pub fn diagram_handlers() -> HashMap<String, &'static dyn FenceHandler> {
+    DIAGRAM_LANGUAGES
+        .iter()
+        .map(|language| (language.to_string(), &DiagramFence as &dyn FenceHandler))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, DiagnosticKind, Options};
+    use pulldown_cmark::Parser;
+
+    #[test]
+    #[cfg(feature = "fence_formats")]
+    fn turns_fences_into_objects() {
+        let chart = ObjectFence::new("chart", FenceFormat::Yaml);
+        let quote = ObjectFence::new("pullQuote", FenceFormat::Raw);
+        let mut fence_handlers: HashMap<String, &dyn FenceHandler> = HashMap::new();
+        fence_handlers.insert("chart".to_owned(), &chart);
+        fence_handlers.insert("quote".to_owned(), &quote);
+        let markdown_input = "```chart {.wide}\nkind: bar\nvalues: [1, 2]\nlabels: [\"a, b\", c]\n```\n\n```quote\nHi\n```\n\n```rust\nfn main() {}\n```\nAfter";
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                fence_handlers,
+                ..Options::default()
+            },
+        );
+
+        let types: Vec<&str> = portabletext_output
+            .iter()
+            .map(|block| block._type.as_str())
+            .collect();
+        assert_eq!(vec!["chart", "pullQuote", "code", "block"], types);
+        let chart = &portabletext_output[0];
+        assert_eq!(None, chart.style);
+        assert_eq!(Some(&Value::from("bar")), chart.fields.get("kind"));
+        assert_eq!(
+            Some(&Value::from(vec![1i64, 2])),
+            chart.fields.get("values")
+        );
+        assert_eq!(
+            Some(&Value::from(vec!["a, b", "c"])),
+            chart.fields.get("labels")
+        );
+        assert_eq!(
+            Some(&Value::from("Hi\n")),
+            portabletext_output[1].fields.get("value")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fence_formats")]
+    fn keeps_invalid_fences_as_code() {
+        let config = ObjectFence::new("config", FenceFormat::Json);
+        let mut fence_handlers: HashMap<String, &dyn FenceHandler> = HashMap::new();
+        fence_handlers.insert("config".to_owned(), &config);
+        let markdown_input = format!(
+            "```config\n{{\"a\": \n```\n\n```config\n{{\"_type\": \"x\"}}\n```\n\n```config\n{}\n```",
+            "[".repeat(10_000)
+        );
+
+        let mut portabletext_output = vec![];
+        let diagnostics = portabletext::try_push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(&markdown_input),
+            Options {
+                fence_handlers,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        assert!(portabletext_output
+            .iter()
+            .all(|block| block._type == "code"));
+        assert_eq!(3, diagnostics.len());
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.kind == DiagnosticKind::InvalidFence));
+        assert!(diagnostics[0].message.starts_with("invalid config block: "));
+        assert!(diagnostics[1]
+            .message
+            .ends_with("`_type` is a reserved field"));
+    }
+
+    #[test]
+    #[cfg(not(feature = "fence_formats"))]
+    fn needs_fence_formats_to_parse() {
+        let config = ObjectFence::new("config", FenceFormat::Yaml);
+        let mut fence_handlers: HashMap<String, &dyn FenceHandler> = HashMap::new();
+        fence_handlers.insert("config".to_owned(), &config);
+
+        let mut portabletext_output = vec![];
+        let diagnostics = portabletext::try_push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new("```config\na: 1\n```"),
+            Options {
+                fence_handlers,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!("code", portabletext_output[0]._type);
+        assert_eq!(DiagnosticKind::InvalidFence, diagnostics[0].kind);
+    }
+
+    #[test]
+    fn turns_diagram_fences_into_diagrams() {
+        let markdown_input =
+            "```mermaid\ngraph TD;\n  A-->B;\n```\n\n```dot\ndigraph { a -> b }\n```\n\n```js\nx\n```";
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                fence_handlers: diagram_handlers(),
+                ..Options::default()
+            },
+        );
+
+        let diagrams: Vec<(&str, Option<&str>, Option<&str>)> = portabletext_output
+            .iter()
+            .map(|block| {
+                (
+                    block._type.as_str(),
+                    block.fields.get("engine").and_then(Value::as_str),
+                    block.fields.get("source").and_then(Value::as_str),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("diagram", Some("mermaid"), Some("graph TD;\n  A-->B;")),
+                ("diagram", Some("graphviz"), Some("digraph { a -> b }")),
+                ("code", None, None),
+            ],
+            diagrams
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "fence_formats", feature = "serde_serialization"))]
+    fn serializes_fields_next_to_the_type() {
+        let block = ObjectFence::new("callToAction", FenceFormat::Json)
+            .block(
+                "",
+                parse(FenceFormat::Json, r#"{"label": "Buy", "price": 5}"#).unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            r#"{"_type":"callToAction","label":"Buy","price":5}"#,
+            serde_json::to_string(&block).unwrap()
+        );
+    }
+}
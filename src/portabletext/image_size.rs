@@ -0,0 +1,276 @@
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ImageFormat {
+    /// Only used as an output format, `image_size` can't read AVIF headers.
+    Avif,
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Svg,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Avif => "avif",
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Svg => "svg",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Svg => "image/svg+xml",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ImageSize {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Reads the dimensions from the header of a PNG, JPEG, GIF, WebP or SVG
+/// image, without decoding it.
+pub fn image_size(bytes: &[u8]) -> Option<ImageSize> {
+    let (format, (width, height)) = if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        (ImageFormat::Png, png_size(bytes)?)
+    } else if bytes.starts_with(b"\xff\xd8") {
+        (ImageFormat::Jpeg, jpeg_size(bytes)?)
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        (ImageFormat::Gif, gif_size(bytes)?)
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        (ImageFormat::Webp, webp_size(bytes)?)
+    } else {
+        (ImageFormat::Svg, svg_size(bytes)?)
+    };
+
+    Some(ImageSize {
+        format,
+        width,
+        height,
+    })
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 2)?;
+    Some(u32::from(b[0]) << 8 | u32::from(b[1]))
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 2)?;
+    Some(u32::from(b[1]) << 8 | u32::from(b[0]))
+}
+
+fn le_u24(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 3)?;
+    Some(u32::from(b[2]) << 16 | u32::from(b[1]) << 8 | u32::from(b[0]))
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn png_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    // the IHDR chunk always comes first
+    if bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    Some((be_u32(bytes, 16)?, be_u32(bytes, 20)?))
+}
+
+fn gif_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    Some((le_u16(bytes, 6)?, le_u16(bytes, 8)?))
+}
+
+fn jpeg_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut at = 2;
+    loop {
+        if *bytes.get(at)? != 0xff {
+            return None;
+        }
+        let marker = *bytes.get(at + 1)?;
+        match marker {
+            // fill bytes
+            0xff => at += 1,
+            // markers without a length
+            0x01 | 0xd0..=0xd7 => at += 2,
+            // start of frame, except DHT (c4), JPG (c8) and DAC (cc)
+            0xc0..=0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => {
+                return Some((be_u16(bytes, at + 7)?, be_u16(bytes, at + 5)?));
+            }
+            // start of scan or end of image before any frame header
+            0xd9 | 0xda => return None,
+            _ => at += 2 + be_u16(bytes, at + 2)? as usize,
+        }
+    }
+}
+
+fn webp_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    match bytes.get(12..16)? {
+        b"VP8 " => Some((le_u16(bytes, 26)? & 0x3fff, le_u16(bytes, 28)? & 0x3fff)),
+        b"VP8L" => {
+            let bits = be_u32(bytes, 21)?.swap_bytes();
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8X" => Some((le_u24(bytes, 24)? + 1, le_u24(bytes, 27)? + 1)),
+        _ => None,
+    }
+}
+
+fn svg_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let start = text.find("<svg")?;
+    let end = start + text[start..].find('>')?;
+    let tag = &text[start..end];
+
+    let width = attribute(tag, "width").and_then(svg_length);
+    let height = attribute(tag, "height").and_then(svg_length);
+    let view_box: Option<Vec<f64>> = attribute(tag, "viewBox").map(|view_box| {
+        view_box
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .filter_map(|part| part.parse().ok())
+            .collect()
+    });
+    let view_box = view_box.filter(|view_box| view_box.len() == 4);
+
+    let (width, height) = match (width, height, view_box) {
+        (Some(width), Some(height), _) => (width, height),
+        (Some(width), None, Some(v)) => (width, width * v[3] / v[2]),
+        (None, Some(height), Some(v)) => (height * v[2] / v[3], height),
+        (None, None, Some(v)) => (v[2], v[3]),
+        _ => return None,
+    };
+    Some((width.round() as u32, height.round() as u32))
+}
+
+fn attribute<'t>(tag: &'t str, name: &str) -> Option<&'t str> {
+    let mut rest = tag;
+    while let Some(index) = rest.find(name) {
+        let preceded_by_space = rest[..index].ends_with(char::is_whitespace);
+        rest = &rest[index + name.len()..];
+        let value = rest.trim_start();
+        if !preceded_by_space || !value.starts_with('=') {
+            continue;
+        }
+        let value = value[1..].trim_start();
+        let quote = value.chars().next()?;
+        if quote != '"' && quote != '\'' {
+            continue;
+        }
+        let value = &value[1..];
+        return Some(&value[..value.find(quote)?]);
+    }
+    None
+}
+
+/// Absolute lengths only, percentages and font relative units are unknown
+/// without a layout.
+fn svg_length(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let value = value.strip_suffix("px").unwrap_or(value);
+    value.parse().ok().filter(|length: &f64| *length > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_png() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+
+        assert_eq!(
+            Some(ImageSize {
+                format: ImageFormat::Png,
+                width: 640,
+                height: 480
+            }),
+            image_size(&png)
+        );
+    }
+
+    #[test]
+    fn reads_jpeg() {
+        let jpeg = [
+            0xff, 0xd8, // SOI
+            0xff, 0xe0, 0x00, 0x04, 0x00, 0x00, // APP0 with 2 bytes of data
+            0xff, 0xc2, 0x00, 0x11, 0x08, 0x01, 0xe0, 0x02, 0x80, // SOF2 480x640
+        ];
+
+        assert_eq!(
+            Some((ImageFormat::Jpeg, 640, 480)),
+            image_size(&jpeg).map(|s| (s.format, s.width, s.height))
+        );
+    }
+
+    #[test]
+    fn reads_gif() {
+        let gif = b"GIF89a\x80\x02\xe0\x01";
+
+        assert_eq!(
+            Some((ImageFormat::Gif, 640, 480)),
+            image_size(gif).map(|s| (s.format, s.width, s.height))
+        );
+    }
+
+    #[test]
+    fn reads_webp() {
+        let mut lossy = b"RIFF\0\0\0\0WEBPVP8 \0\0\0\0\0\0\0\x9d\x01\x2a".to_vec();
+        lossy.extend_from_slice(&[0x80, 0x02, 0xe0, 0x01]);
+        let mut extended = b"RIFF\0\0\0\0WEBPVP8X\0\0\0\0\0\0\0\0".to_vec();
+        extended.extend_from_slice(&[0x7f, 0x02, 0x00, 0xdf, 0x01, 0x00]);
+        // 640x480: (640 - 1) | (480 - 1) << 14, little endian
+        let bits: u32 = 639 | 479 << 14;
+        let mut lossless = b"RIFF\0\0\0\0WEBPVP8L\0\0\0\0\x2f".to_vec();
+        lossless.extend_from_slice(&bits.to_le_bytes());
+
+        for webp in &[lossy, extended, lossless] {
+            assert_eq!(
+                Some((ImageFormat::Webp, 640, 480)),
+                image_size(webp).map(|s| (s.format, s.width, s.height))
+            );
+        }
+    }
+
+    #[test]
+    fn reads_svg() {
+        let sized = br#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" width="640px" height='480'></svg>"#;
+        let view_box = br#"<svg viewBox="0 0 64 48" xmlns="http://www.w3.org/2000/svg"/>"#;
+        let half = br#"<svg width="320" viewBox="0,0,64,48"/>"#;
+
+        assert_eq!(
+            Some((640, 480)),
+            image_size(sized).map(|s| (s.width, s.height))
+        );
+        assert_eq!(
+            Some((64, 48)),
+            image_size(view_box).map(|s| (s.width, s.height))
+        );
+        assert_eq!(
+            Some((320, 240)),
+            image_size(half).map(|s| (s.width, s.height))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_data() {
+        assert_eq!(None, image_size(b"hello"));
+        assert_eq!(None, image_size(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(None, image_size(b"\xff\xd8\xff\xe0\x00\x10"));
+    }
+}
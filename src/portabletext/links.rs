@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use super::url;
+use super::{LinkContext, LinkResolver, ResolveError, ResolvedLink};
+
+/// Whether `href` points to a web page on a host that is not one of
+/// `site_hosts` or their subdomains. Relative links are never external.
+pub fn is_external(href: &str, site_hosts: &[String]) -> bool {
+    match url::scheme(href).as_deref() {
+        Some("http") | Some("https") | None => {}
+        Some(_) => return false,
+    }
+    let host = match url::host(href) {
+        Some(host) => host,
+        None => return false,
+    };
+
+    !site_hosts.iter().any(|site_host| {
+        let site_host = site_host.to_ascii_lowercase();
+        host == site_host
+            || host
+                .strip_suffix(&site_host)
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    })
+}
+
+/// Resolves links for a Markdown file that is part of a content folder.
+///
+/// Links to other Markdown files (`../post.md`) become internal links to the
+/// document registered for that file in `documents`, and fail to resolve if
+/// there is none. Other relative links are resolved against `base_url`, when
+/// set. Absolute links and fragments (`#usage`) are kept as written.
+#[derive(Debug, Default, Clone)]
+pub struct SiteLinkResolver {
+    /// URL the converted document is published at.
+    pub base_url: Option<String>,
+    /// Path of the converted Markdown file below the content folder, e.g.
+    /// `blog/hello.md`.
+    pub document_path: String,
+    /// Document ids by Markdown path below the content folder.
+    pub documents: HashMap<String, String>,
+}
+
+impl SiteLinkResolver {
+    pub fn new(document_path: impl Into<String>) -> Self {
+        Self {
+            document_path: document_path.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    pub fn document(mut self, path: impl Into<String>, id: impl Into<String>) -> Self {
+        self.documents.insert(path.into(), id.into());
+        self
+    }
+
+    /// The path below the content folder a relative Markdown link points to.
+    fn markdown_path(&self, href: &str) -> Option<String> {
+        let (path, _) = url::split_suffix(href);
+        if !(path.ends_with(".md") || path.ends_with(".markdown")) {
+            return None;
+        }
+
+        let joined = url::join(&format!("/{}", self.document_path), path);
+        Some(joined.trim_start_matches('/').to_owned())
+    }
+}
+
+impl LinkResolver for SiteLinkResolver {
+    fn resolve_link(&self, context: &LinkContext) -> Result<ResolvedLink, ResolveError> {
+        let href = context.href;
+        if !url::is_relative(href) || href.starts_with('#') {
+            return Ok(ResolvedLink::Href(href.to_owned()));
+        }
+
+        if let Some(path) = self.markdown_path(href) {
+            return match self.documents.get(&path) {
+                Some(id) => Ok(ResolvedLink::Internal(id.to_owned())),
+                None => Err(ResolveError::new(href, format!("no document for {}", path))),
+            };
+        }
+
+        match &self.base_url {
+            Some(base_url) => Ok(ResolvedLink::Href(url::join(base_url, href))),
+            None => Ok(ResolvedLink::Href(href.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, DiagnosticKind, MarkDef, Options, Reference};
+    use pulldown_cmark::Parser;
+
+    fn resolver() -> SiteLinkResolver {
+        SiteLinkResolver::new("blog/2021/hello.md")
+            .base_url("https://example.com/blog/2021/hello/")
+            .document("blog/2020/post.md", "post-2020")
+            .document("about.md", "about")
+    }
+
+    fn resolve(href: &str) -> Result<ResolvedLink, ResolveError> {
+        resolver().resolve_link(&LinkContext {
+            href,
+            title: None,
+            block: None,
+            source_position: None,
+        })
+    }
+
+    #[test]
+    fn resolves_links() {
+        assert_eq!(
+            Ok(ResolvedLink::Internal("post-2020".to_owned())),
+            resolve("../2020/post.md#intro")
+        );
+        assert_eq!(
+            Ok(ResolvedLink::Internal("about".to_owned())),
+            resolve("/about.md")
+        );
+        assert_eq!(
+            Ok(ResolvedLink::Href(
+                "https://example.com/blog/2021/feed.xml".to_owned()
+            )),
+            resolve("../feed.xml")
+        );
+        assert_eq!(
+            Ok(ResolvedLink::Href("https://example.org/".to_owned())),
+            resolve("https://example.org/")
+        );
+        assert_eq!(
+            Ok(ResolvedLink::Href("#usage".to_owned())),
+            resolve("#usage")
+        );
+        assert!(resolve("missing.md").is_err());
+    }
+
+    #[test]
+    fn detects_external_links() {
+        let site_hosts = vec!["example.com".to_owned(), "Docs.example.org".to_owned()];
+
+        assert!(is_external("https://rust-lang.org/", &site_hosts));
+        assert!(is_external("//cdn.example.net/a.js", &site_hosts));
+        assert!(is_external("https://notexample.com", &site_hosts));
+        assert!(!is_external("https://www.example.com/blog", &site_hosts));
+        assert!(!is_external("http://EXAMPLE.com", &site_hosts));
+        assert!(!is_external("https://docs.example.org", &site_hosts));
+        assert!(!is_external("/about", &site_hosts));
+        assert!(!is_external("mailto:me@rust-lang.org", &site_hosts));
+    }
+
+    #[test]
+    fn marks_external_links() {
+        let markdown_input =
+            "[Rust](https://www.rust-lang.org/) and [us](https://example.com/about)";
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                site_hosts: vec!["example.com".to_owned()],
+                ..Options::default()
+            },
+        );
+
+        let flags: Vec<(bool, bool)> = portabletext_output[0]
+            .mark_defs
+            .iter()
+            .map(|mark_def| match mark_def {
+                MarkDef::Link(link) => (link.blank, link.external),
+                mark_def => panic!("{:?}", mark_def),
+            })
+            .collect();
+        assert_eq!(vec![(true, true), (false, false)], flags);
+    }
+
+    #[test]
+    fn writes_internal_links_and_reports_broken_ones() {
+        let resolver = resolver();
+        let markdown_input = "See [the post](../2020/post.md) and [this](./gone.md).";
+
+        let mut portabletext_output = vec![];
+        let diagnostics = portabletext::try_push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                link_resolver: &resolver,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        let block = &portabletext_output[0];
+        match &block.mark_defs[0] {
+            MarkDef::InternalLink(link) => {
+                assert_eq!("internalLink", link._type);
+                assert_eq!(
+                    Reference {
+                        _ref: "post-2020".to_owned()
+                    },
+                    link.reference
+                );
+            }
+            mark_def => panic!("{:?}", mark_def),
+        }
+        match &block.mark_defs[1] {
+            MarkDef::Link(link) => assert_eq!("./gone.md", link.href),
+            mark_def => panic!("{:?}", mark_def),
+        }
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(DiagnosticKind::UnresolvedLink, diagnostics[0].kind);
+        assert_eq!(
+            "cannot resolve ./gone.md: no document for blog/2021/gone.md",
+            diagnostics[0].message
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use super::image_size::{image_size, ImageSize};
+use super::url::percent_decode;
+use super::{AssetReferenceResolver, ImageContext, Picture, ResolveError};
+
+/// Resolves image references to files below `base_dir` and reads their real
+/// dimensions from the file header.
+///
+/// `/assets/rock.jpg` and `assets/rock.jpg` both resolve to
+/// `base_dir/assets/rock.jpg`, and `my%20rock.jpg` to `base_dir/my rock.jpg`.
+/// References with a scheme (`https://…`) are not
+/// local and fail to resolve. The reference itself is kept as `src`.
+/// How much of an image is read to find its dimensions.
+const HEADER_LENGTH: u64 = 64 * 1024;
+
+pub struct LocalImageResolver {
+    pub base_dir: PathBuf,
+}
+
+impl LocalImageResolver {
+    pub fn new(base_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            base_dir: base_dir.into(),
+        }
+    }
+
+    /// The file a reference points to. References escaping `base_dir` with
+    /// `..` are rejected.
+    pub fn path(&self, reference: &str) -> Result<PathBuf, ResolveError> {
+        if reference.contains("://") || reference.starts_with("data:") {
+            return Err(ResolveError::new(reference, "not a local path"));
+        }
+
+        let path = percent_decode(reference.split(['?', '#']).next().unwrap_or(reference));
+        let path = Path::new(path.trim_start_matches('/'));
+        if path
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(ResolveError::new(
+                reference,
+                "path leaves the base directory",
+            ));
+        }
+
+        Ok(self.base_dir.join(path))
+    }
+
+    /// Format and dimensions of the referenced image.
+    pub fn size(&self, reference: &str) -> Result<ImageSize, ResolveError> {
+        let path = self.path(reference)?;
+        let mut bytes = vec![];
+        File::open(&path)
+            .and_then(|file| file.take(HEADER_LENGTH).read_to_end(&mut bytes))
+            .map_err(|e| ResolveError::new(reference, format!("{}: {}", path.display(), e)))?;
+        image_size(&bytes).ok_or_else(|| ResolveError::new(reference, "unknown image format"))
+    }
+}
+
+impl AssetReferenceResolver for LocalImageResolver {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        self.path(context.reference)?;
+        Ok(context.reference.to_owned())
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        let size = self.size(context.reference)?;
+        Ok(Picture {
+            src: context.reference.to_owned(),
+            alt: context.alt.to_owned(),
+            width: size.width,
+            height: size.height,
+            sources: vec![],
+            placeholder: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, MarkDef, Options};
+    use pulldown_cmark::Parser;
+    use std::{env, fs};
+
+    fn base_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "pulldown_cmark_portabletext-local-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("assets/rock.gif"), b"GIF89a\x80\x02\xe0\x01").unwrap();
+        fs::write(dir.join("assets/my rock.gif"), b"GIF89a\x10\x00\x20\x00").unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_dimensions_from_disk() {
+        let resolver = LocalImageResolver::new(base_dir());
+        let markdown_input = "![A rock](/assets/rock.gif?v=2)";
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                asset_resolver: &resolver,
+                ..Options::default()
+            },
+        );
+
+        match &portabletext_output[0].mark_defs[0] {
+            MarkDef::Image(image) => {
+                assert_eq!("/assets/rock.gif?v=2", image.src);
+                assert_eq!((640, 480), (image.picture.width, image.picture.height));
+                assert_eq!("A rock", image.picture.alt);
+            }
+            mark_def => panic!("{:?}", mark_def),
+        }
+    }
+
+    #[test]
+    fn rejects_missing_and_remote_images() {
+        let resolver = LocalImageResolver::new(base_dir());
+
+        assert!(resolver.size("assets/missing.png").is_err());
+        assert!(resolver.size("https://example.com/rock.gif").is_err());
+        assert!(resolver.size("../secret.gif").is_err());
+        assert!(resolver.size("./assets/rock.gif").is_ok());
+        assert!(resolver.size("assets/%2E%2E/%2E%2E/secret.gif").is_err());
+    }
+
+    #[test]
+    fn decodes_escaped_paths() {
+        let resolver = LocalImageResolver::new(base_dir());
+
+        let size = resolver.size("assets/my%20rock.gif").unwrap();
+        assert_eq!((16, 32), (size.width, size.height));
+    }
+
+    #[test]
+    fn reads_dimensions_of_large_files() {
+        let dir = base_dir();
+        let mut bytes = b"GIF89a\x80\x02\xe0\x01".to_vec();
+        bytes.resize(HEADER_LENGTH as usize * 4, 0);
+        fs::write(dir.join("assets/large.gif"), bytes).unwrap();
+        let resolver = LocalImageResolver::new(dir);
+
+        let size = resolver.size("assets/large.gif").unwrap();
+        assert_eq!((640, 480), (size.width, size.height));
+    }
+}
@@ -0,0 +1,411 @@
+//! `$inline$` and `$$display$$` math, which pulldown-cmark does not parse. The
+//! Markdown is rewritten with `prepare` first, so emphasis and escapes leave
+//! the TeX alone, and converted with `Options::math`. `try_push_markdown` does
+//! both.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use pulldown_cmark::Event::{End, Html, Start};
+use pulldown_cmark::{LinkType, Parser, Tag};
+
+use super::value::Value;
+use super::{BlockNode, InlineObject};
+
+/// Starts the code spans `prepare` writes inline math as. Code spans of
+/// code starting with it get a second one, which the writer removes again.
+pub(crate) const MARKER: char = '\u{E000}';
+
+/// Rewrites math so it survives Markdown parsing: display math on its own
+/// lines as ```` ```math ```` fences, inline math as marked code spans.
+/// Dollars in code, HTML, autolinks and link destinations are left alone.
+/// Inline math starts with a `$` followed by a
+/// non-space and ends on the same line at a `$` following a non-space and not
+/// followed by a digit, so `$5 and $10` stays text. `\$` is a literal dollar.
+///
+/// Inline math is written as code spans starting with the private use
+/// character U+E000, which `Options::math` turns back into math. Code spans
+/// starting with that character are escaped, so they stay code.
+///
+/// Source positions of the converted events refer to the rewritten Markdown.
+pub fn prepare(markdown: &str) -> String {
+    let verbatim = verbatim_ranges(markdown);
+    let mut output = String::with_capacity(markdown.len());
+    let mut lines = markdown.split_inclusive('\n');
+    // where the line starts in `markdown`
+    let mut offset = 0;
+
+    while let Some(line) = lines.next() {
+        let start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        let indent = &line[..line.len() - line.trim_start().len()];
+
+        if in_ranges(&verbatim, start + indent.len()).is_some() {
+            output.push_str(line);
+            continue;
+        }
+
+        if let Some(first) = trimmed.strip_prefix("$$") {
+            // a single line `$$tex$$`, or `$$` up to a line ending in `$$`
+            let single = first.strip_suffix("$$").filter(|tex| !tex.is_empty());
+            let tex = match single {
+                Some(tex) => Some(tex.to_owned()),
+                None => display_lines(first, &mut lines.clone()).map(|(tex, count)| {
+                    for _ in 0..count {
+                        offset += lines.next().map_or(0, str::len);
+                    }
+                    tex
+                }),
+            };
+            if let Some(tex) = tex {
+                let tex = tex.trim_matches('\n');
+                let fence = "`".repeat(longest_run(tex, '`').max(2) + 1);
+                output.push_str(&format!("{}{}math\n", indent, fence));
+                for tex_line in tex.lines() {
+                    output.push_str(&format!("{}{}\n", indent, tex_line));
+                }
+                output.push_str(&format!("{}{}\n", indent, fence));
+                continue;
+            }
+        }
+
+        output.push_str(&inline_math(line, start, &verbatim));
+    }
+    output
+}
+
+/// Byte ranges of `markdown` whose dollars are not math: code blocks, HTML,
+/// autolinks and what follows the text of links and images.
+fn verbatim_ranges(markdown: &str) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+    // where the destination of each open link or image starts, innermost last
+    let mut destinations: Vec<usize> = vec![];
+
+    for (event, range) in Parser::new(markdown).into_offset_iter() {
+        match event {
+            Start(Tag::CodeBlock(_)) | Html(_) => ranges.push(range.clone()),
+            Start(Tag::Link(LinkType::Autolink, ..)) | Start(Tag::Link(LinkType::Email, ..)) => {
+                ranges.push(range.clone())
+            }
+            End(Tag::Link(LinkType::Autolink, ..)) | End(Tag::Link(LinkType::Email, ..)) => {}
+            Start(Tag::Link(..)) | Start(Tag::Image(..)) => {
+                destinations.push(range.start);
+                continue;
+            }
+            End(Tag::Link(..)) | End(Tag::Image(..)) => {
+                if let Some(start) = destinations.pop() {
+                    ranges.push(start..range.end);
+                }
+            }
+            _ => {}
+        }
+        // the destination follows everything inside the link
+        if let Some(start) = destinations.last_mut() {
+            *start = (*start).max(range.end);
+        }
+    }
+    ranges
+}
+
+/// The end of the range `index` is in, if any.
+fn in_ranges(ranges: &[Range<usize>], index: usize) -> Option<usize> {
+    ranges
+        .iter()
+        .find(|range| range.contains(&index))
+        .map(|range| range.end)
+}
+
+/// The TeX of display math opened by a `$$` line with `first` after it, and
+/// the number of following lines it takes up to its closing `$$`.
+fn display_lines<'m>(
+    first: &str,
+    lines: &mut impl Iterator<Item = &'m str>,
+) -> Option<(String, usize)> {
+    let mut tex = format!("{}\n", first);
+    for (index, line) in lines.enumerate() {
+        let trimmed = line.trim();
+        if let Some(last) = trimmed.strip_suffix("$$") {
+            tex.push_str(last);
+            return Some((tex, index + 1));
+        }
+        if trimmed.is_empty() {
+            return None;
+        }
+        tex.push_str(line.trim_end_matches(['\n', '\r']).trim_start());
+        tex.push('\n');
+    }
+    None
+}
+
+fn longest_run(text: &str, c: char) -> usize {
+    text.split(|other| other != c)
+        .map(str::len)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Rewrites the inline math of a line starting at `start` in the Markdown,
+/// skipping code spans, escapes and the `verbatim` ranges.
+fn inline_math(line: &str, start: usize, verbatim: &[Range<usize>]) -> String {
+    let mut output = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(index) = rest.find(['`', '\\', '$']) {
+        output.push_str(&rest[..index]);
+        rest = &rest[index..];
+        let position = start + line.len() - rest.len();
+
+        if let Some(end) = in_ranges(verbatim, position) {
+            let length = (end - position).min(rest.len());
+            output.push_str(&rest[..length]);
+            rest = &rest[length..];
+        } else if rest.starts_with('\\') {
+            let length = rest[1..].chars().next().map_or(0, char::len_utf8) + 1;
+            output.push_str(&rest[..length]);
+            rest = &rest[length..];
+        } else if rest.starts_with('`') {
+            let ticks = rest.len() - rest.trim_start_matches('`').len();
+            output.push_str(&rest[..ticks]);
+            match closing_ticks(&rest[ticks..], ticks) {
+                Some(length) => {
+                    output.push_str(&escape_code(&rest[ticks..ticks + length]));
+                    output.push_str(&rest[..ticks]);
+                    rest = &rest[ticks * 2 + length..];
+                }
+                None => rest = &rest[ticks..],
+            }
+        } else {
+            let delimiter = if rest.starts_with("$$") { "$$" } else { "$" };
+            match closing_dollar(&rest[delimiter.len()..], delimiter) {
+                Some(end) => {
+                    let tex = &rest[delimiter.len()..delimiter.len() + end];
+                    output.push_str(&code_span(tex));
+                    rest = &rest[delimiter.len() * 2 + end..];
+                }
+                None => {
+                    output.push_str(delimiter);
+                    rest = &rest[delimiter.len()..];
+                }
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Where a run of exactly `ticks` backticks closing a code span starts.
+fn closing_ticks(text: &str, ticks: usize) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(index) = text[offset..].find('`') {
+        let start = offset + index;
+        let run = text[start..].len() - text[start..].trim_start_matches('`').len();
+        if run == ticks {
+            return Some(start);
+        }
+        offset = start + run;
+    }
+    None
+}
+
+/// Where the math after an opening `delimiter` ends, if it does.
+fn closing_dollar(text: &str, delimiter: &str) -> Option<usize> {
+    if text.starts_with(char::is_whitespace) || text.starts_with('$') {
+        return None;
+    }
+
+    let mut previous = ' ';
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '\n' => return None,
+            '$' if escaped => {}
+            '$' if text[index..].starts_with(delimiter) => {
+                let after = text[index + delimiter.len()..].chars().next();
+                if previous.is_whitespace() || after.is_some_and(|c| c.is_ascii_digit()) {
+                    return None;
+                }
+                return Some(index);
+            }
+            _ => {}
+        }
+        // a backslash only escapes the character right after it
+        escaped = c == '\\' && !escaped;
+        previous = c;
+    }
+    None
+}
+
+/// Doubles the `MARKER` a code span of code starts with.
+fn escape_code(code: &str) -> String {
+    if code.trim_start_matches(' ').starts_with(MARKER) {
+        code.replacen(MARKER, "\u{E000}\u{E000}", 1)
+    } else {
+        code.to_owned()
+    }
+}
+
+/// A code span the writer turns back into inline math.
+fn code_span(tex: &str) -> String {
+    let ticks = "`".repeat(longest_run(tex, '`') + 1);
+    let padding = if tex.ends_with('`') { " " } else { "" };
+    format!("{}{}{}{}{}{}", ticks, padding, MARKER, tex, padding, ticks)
+}
+
+/// The TeX of a code span `prepare` wrote inline math as, `None` for code.
+pub(crate) fn inline_tex(code: &str) -> Option<&str> {
+    let tex = code.trim().strip_prefix(MARKER)?;
+    if tex.starts_with(MARKER) {
+        None
+    } else {
+        Some(tex)
+    }
+}
+
+/// A code span of code, without the `MARKER` `prepare` escaped it with.
+pub(crate) fn unescape_code(code: &str) -> String {
+    if code.trim_start_matches(' ').starts_with("\u{E000}\u{E000}") {
+        code.replacen(MARKER, "", 1)
+    } else {
+        code.to_owned()
+    }
+}
+
+/// An inline `math` object.
+pub(crate) fn inline(tex: &str) -> InlineObject {
+    let mut object = InlineObject::new("math");
+    object.fields = tex_fields(tex);
+    object
+}
+
+/// A block `math` object.
+pub(crate) fn block(tex: &str) -> BlockNode {
+    let mut block = BlockNode::object("math".to_owned());
+    block.fields = tex_fields(tex);
+    block
+}
+
+fn tex_fields(tex: &str) -> BTreeMap<String, Value> {
+    let mut fields = BTreeMap::new();
+    fields.insert("tex".to_owned(), Value::from(tex));
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, Decorators, Options};
+
+    fn convert(markdown_input: &str) -> Vec<BlockNode> {
+        let mut portabletext_output = vec![];
+        portabletext::try_push_markdown(
+            &mut portabletext_output,
+            markdown_input,
+            pulldown_cmark::Options::empty(),
+            Options {
+                math: true,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        portabletext_output
+    }
+
+    fn tex(fields: &BTreeMap<String, Value>) -> Option<&str> {
+        fields.get("tex").and_then(Value::as_str)
+    }
+
+    #[test]
+    fn finds_inline_math() {
+        let line = inline_math("a $x_1 + y_2$ costs \\$5, `$code$` and $$E$$", 0, &[]);
+        let marked: Vec<&str> = line.split(MARKER).collect();
+
+        assert_eq!(
+            vec!["a `", "x_1 + y_2` costs \\$5, `$code$` and `", "E`"],
+            marked
+        );
+        assert_eq!("10:30 $ and $", inline_math("10:30 $ and $", 0, &[]));
+        assert_eq!("$5 and $10", inline_math("$5 and $10", 0, &[]));
+        assert_eq!("`\u{E000}a \\\\`", inline_math("$a \\\\$", 0, &[]));
+        assert_eq!("$a \\$", inline_math("$a \\$", 0, &[]));
+    }
+
+    #[test]
+    fn leaves_code_html_and_link_destinations_alone() {
+        let unchanged = [
+            "Para\n\n    code $x$ here\n",
+            "- item\n\n      code $x$\n",
+            "[l](https://x.com/$a$b \"$t$\")",
+            "![a](/$p$.png)",
+            "<https://x.com/$a$>",
+            "<span title=\"$a$\">text</span>",
+            "<div>\n$z$\n</div>\n",
+        ];
+        for markdown_input in unchanged {
+            assert_eq!(markdown_input, prepare(markdown_input));
+        }
+
+        assert_eq!(
+            "[`\u{E000}x`](/$p$) <b title=\"$a$\">`\u{E000}y`</b>",
+            prepare("[$x$](/$p$) <b title=\"$a$\">$y$</b>")
+        );
+    }
+
+    #[test]
+    fn keeps_marks_around_inline_math() {
+        let blocks = convert("[$x$](https://example.com) and *$y$*");
+
+        let children = &blocks[0].children;
+        assert_eq!(
+            ("math", "math"),
+            (children[0]._type.as_str(), children[2]._type.as_str())
+        );
+        assert_eq!(Some("x"), tex(&blocks[0].child_fields[&0]));
+        assert_eq!(
+            vec![Decorators::LinkReference(
+                blocks[0].mark_defs[0].key().to_owned()
+            )],
+            children[0].marks
+        );
+        assert_eq!(vec![Decorators::Emphasis], children[2].marks);
+    }
+
+    #[test]
+    fn keeps_code_spans_starting_with_the_marker() {
+        let blocks = convert("`\u{E000}code` and `` \u{E000}more ``");
+
+        let children = &blocks[0].children;
+        assert!(children.iter().all(|child| child._type == "span"));
+        assert_eq!("\u{E000}code", children[0].text);
+        assert_eq!("\u{E000}more", children[2].text);
+    }
+
+    #[test]
+    fn keeps_tex_untouched() {
+        let blocks = convert(
+            "Let $a_b * c_d$ be *small*.\n\n$$\n\\sum_{i=1}^n a_i\n$$\n\n```\n$not math$\n```",
+        );
+
+        assert_eq!(3, blocks.len());
+        let children = &blocks[0].children;
+        assert_eq!("math", children[1]._type);
+        assert_eq!(Some("a_b * c_d"), tex(&blocks[0].child_fields[&1]));
+        assert_eq!(" be ", children[2].text);
+        assert_eq!("math", blocks[1]._type);
+        assert_eq!(Some("\\sum_{i=1}^n a_i"), tex(&blocks[1].fields));
+        assert_eq!(Some("$not math$\n".to_owned()), blocks[2].code);
+    }
+
+    #[test]
+    fn writes_single_line_and_indented_display_math() {
+        let blocks = convert("$$x^2$$\n\n- item\n\n  $$\n  a\n  b\n  $$\n\n$$ unclosed");
+
+        assert_eq!(Some("x^2"), tex(&blocks[0].fields));
+        assert_eq!("math", blocks[2]._type);
+        assert_eq!(Some("a\nb"), tex(&blocks[2].fields));
+        assert_eq!(
+            "$$ unclosed",
+            portabletext::plain_text::block_text(&blocks[3])
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+
+use super::{BlockNode, Decorators, SpanNode};
+
+/// Cleans up the spans of already converted blocks:
+///
+/// - marks are deduplicated and sorted canonically (decorators first, then
+///   annotation references in their original order),
+/// - empty spans are removed,
+/// - adjacent spans with identical marks are merged,
+/// - mark defs no span refers to are dropped.
+///
+/// Only children with `_type: "span"` are merged or removed, so inline
+/// objects such as figures are left untouched.
+pub fn normalize(blocks: &mut [BlockNode]) {
+    for block in blocks {
+        normalize_block(block);
+        normalize(&mut block.content);
+    }
+}
+
+pub fn normalize_block(block: &mut BlockNode) {
+    let mut children: Vec<SpanNode> = Vec::with_capacity(block.children.len());
+    let mut child_fields = BTreeMap::new();
+
+    for (index, mut span) in block.children.drain(..).enumerate() {
+        sort_marks(&mut span.marks);
+
+        if is_text_span(&span) && span.text.is_empty() {
+            continue;
+        }
+
+        match children.last_mut() {
+            Some(last) if is_text_span(last) && is_text_span(&span) && last.marks == span.marks => {
+                last.text += &span.text;
+            }
+            _ => {
+                if let Some(fields) = block.child_fields.remove(&index) {
+                    child_fields.insert(children.len(), fields);
+                }
+                children.push(span);
+            }
+        }
+    }
+    block.children = children;
+    block.child_fields = child_fields;
+
+    let children = &block.children;
+    block.mark_defs.retain(|mark_def| {
+        let key = mark_def.key();
+        children
+            .iter()
+            .any(|span| span.marks.iter().any(|mark| mark_key(mark) == Some(key)))
+    });
+}
+
+fn is_text_span(span: &SpanNode) -> bool {
+    span._type == "span"
+}
+
+fn sort_marks(marks: &mut Vec<Decorators>) {
+    let mut seen: Vec<Decorators> = Vec::with_capacity(marks.len());
+    marks.retain(|mark| {
+        if seen.contains(mark) {
+            false
+        } else {
+            seen.push(mark.clone());
+            true
+        }
+    });
+    marks.sort_by_key(mark_rank);
+}
+
+fn mark_rank(mark: &Decorators) -> u8 {
+    match mark {
+        Decorators::Strong => 0,
+        Decorators::Emphasis => 1,
+        Decorators::Underline => 2,
+        Decorators::Strike => 3,
+        Decorators::Code => 4,
+        Decorators::Custom(_) => 5,
+        Decorators::LinkReference(_)
+        | Decorators::AssetReference(_)
+        | Decorators::AnnotationReference(_) => 6,
+    }
+}
+
+fn mark_key(mark: &Decorators) -> Option<&str> {
+    match mark {
+        Decorators::LinkReference(key)
+        | Decorators::AssetReference(key)
+        | Decorators::AnnotationReference(key) => Some(key),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, MarkDef, MarkDefLink, Options};
+    use pulldown_cmark::Parser;
+
+    fn span(text: &str, marks: Vec<Decorators>) -> SpanNode {
+        SpanNode::span(text.to_owned(), marks)
+    }
+
+    #[test]
+    fn merges_adjacent_spans_with_identical_marks() {
+        let mut blocks = vec![BlockNode::default("normal".to_owned()).with_children(vec![
+            span("a", vec![Decorators::Emphasis, Decorators::Strong]),
+            span("", vec![]),
+            span(" b", vec![Decorators::Strong, Decorators::Emphasis]),
+            span(" c", vec![]),
+            span(" d", vec![]),
+        ])];
+
+        normalize(&mut blocks);
+
+        assert_eq!(
+            vec![
+                span("a b", vec![Decorators::Strong, Decorators::Emphasis]),
+                span(" c d", vec![]),
+            ],
+            blocks[0].children
+        );
+    }
+
+    #[test]
+    fn keeps_inline_objects_and_drops_unused_mark_defs() {
+        let link = |key: &str| {
+            MarkDef::Link(MarkDefLink {
+                _key: key.to_owned(),
+                _type: "link".to_owned(),
+                href: "https://example.com".to_owned(),
+                blank: false,
+                external: false,
+            })
+        };
+        let mut block = BlockNode::default("normal".to_owned()).with_children(vec![
+            span("", vec![]),
+            SpanNode::object("figure".to_owned()),
+            span("linked", vec![Decorators::LinkReference("used".to_owned())]),
+        ]);
+        block.mark_defs = vec![link("unused"), link("used")];
+        block.child_fields.insert(1, BTreeMap::new());
+
+        normalize_block(&mut block);
+
+        assert_eq!(2, block.children.len());
+        assert_eq!(
+            vec![0],
+            block.child_fields.keys().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(vec![link("used")], block.mark_defs);
+    }
+
+    #[test]
+    fn runs_as_part_of_conversion() {
+        let markdown_input = "**a&amp;b** and [some\nlink](https://example.com)";
+
+        let parser = Parser::new(markdown_input);
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            parser,
+            Options {
+                normalize: true,
+                ..Options::default()
+            },
+        );
+
+        let texts: Vec<&str> = portabletext_output[0]
+            .children
+            .iter()
+            .map(|span| span.text.as_str())
+            .collect();
+        assert_eq!(vec!["a&b", " and ", "some link"], texts);
+    }
+}
@@ -0,0 +1,380 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, RgbImage};
+
+use super::local::LocalImageResolver;
+use super::{Asset, AssetReferenceResolver, ImageContext, Picture, Placeholder, ResolveError};
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PlaceholderConfig {
+    /// Longest side of the `lqip` thumbnail, `None` to skip it.
+    pub thumbnail_size: Option<u32>,
+    /// Horizontal and vertical blurhash components (1 to 9 each), `None` to
+    /// skip the blurhash.
+    pub blurhash_components: Option<(u32, u32)>,
+    pub dominant_color: bool,
+}
+
+impl Default for PlaceholderConfig {
+    fn default() -> Self {
+        Self {
+            thumbnail_size: Some(16),
+            blurhash_components: Some((4, 3)),
+            dominant_color: true,
+        }
+    }
+}
+
+/// Decodes a PNG, JPEG, GIF or WebP image and computes its placeholders.
+/// Returns `None` for anything that can't be decoded, like SVG.
+pub fn placeholder(bytes: &[u8], config: &PlaceholderConfig) -> Option<Placeholder> {
+    let image = image::load_from_memory(bytes).ok()?;
+    if image.width() == 0 || image.height() == 0 {
+        return None;
+    }
+    // blurhash and the dominant color only need a rough idea of the image
+    let small = image.resize(32, 32, FilterType::Triangle).to_rgb8();
+
+    Some(Placeholder {
+        lqip: config
+            .thumbnail_size
+            .and_then(|size| thumbnail(&image, size)),
+        blurhash: config
+            .blurhash_components
+            .map(|(x, y)| blurhash(&small, x, y)),
+        dominant_color: if config.dominant_color {
+            Some(dominant_color(&small))
+        } else {
+            None
+        },
+    })
+}
+
+fn thumbnail(image: &DynamicImage, size: u32) -> Option<String> {
+    let mut png = Cursor::new(vec![]);
+    image
+        .thumbnail(size, size)
+        .write_to(&mut png, ImageFormat::Png)
+        .ok()?;
+    Some(format!("data:image/png;base64,{}", base64(png.get_ref())))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// The most common color, with every channel bucketed to 16 levels.
+fn dominant_color(image: &RgbImage) -> String {
+    let mut buckets = vec![(0u32, [0u32; 3]); 16 * 16 * 16];
+    for pixel in image.pixels() {
+        let [r, g, b] = pixel.0;
+        let bucket =
+            &mut buckets[usize::from(r >> 4) << 8 | usize::from(g >> 4) << 4 | usize::from(b >> 4)];
+        bucket.0 += 1;
+        for (sum, channel) in bucket.1.iter_mut().zip(&pixel.0) {
+            *sum += u32::from(*channel);
+        }
+    }
+
+    // the first of equally common buckets wins
+    let (count, sums) = buckets
+        .iter()
+        .rev()
+        .max_by_key(|(count, _)| *count)
+        .copied()
+        .unwrap_or_default();
+    let count = count.max(1);
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        sums[0] / count,
+        sums[1] / count,
+        sums[2] / count
+    )
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let v = f64::from(value) / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> u32 {
+    let v = value.clamp(0.0, 1.0);
+    if v <= 0.003_130_8 {
+        (v * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn base83(value: u32, length: u32, hash: &mut String) {
+    for i in 1..=length {
+        let digit = value / 83u32.pow(length - i) % 83;
+        hash.push(BASE83[digit as usize] as char);
+    }
+}
+
+/// Encodes an image as a blurhash, see https://blurha.sh.
+fn blurhash(image: &RgbImage, x_components: u32, y_components: u32) -> String {
+    let x_components = x_components.clamp(1, 9);
+    let y_components = y_components.clamp(1, 9);
+    let (width, height) = image.dimensions();
+
+    let mut factors = vec![];
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let basis = normalisation
+                    * (PI * f64::from(i) * f64::from(x) / f64::from(width)).cos()
+                    * (PI * f64::from(j) * f64::from(y) / f64::from(height)).cos();
+                for (f, channel) in factor.iter_mut().zip(&pixel.0) {
+                    *f += basis * srgb_to_linear(*channel);
+                }
+            }
+            let scale = 1.0 / f64::from(width * height);
+            factors.push([factor[0] * scale, factor[1] * scale, factor[2] * scale]);
+        }
+    }
+
+    let mut hash = String::new();
+    base83((x_components - 1) + (y_components - 1) * 9, 1, &mut hash);
+
+    let (dc, ac) = factors.split_first().unwrap();
+    let maximum = if ac.is_empty() {
+        base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual = ac
+            .iter()
+            .flat_map(|factor| factor.iter())
+            .fold(0.0f64, |max, f| max.max(f.abs()));
+        let quantised = (actual * 166.0 - 0.5).floor().clamp(0.0, 82.0);
+        base83(quantised as u32, 1, &mut hash);
+        (quantised + 1.0) / 166.0
+    };
+
+    base83(
+        linear_to_srgb(dc[0]) << 16 | linear_to_srgb(dc[1]) << 8 | linear_to_srgb(dc[2]),
+        4,
+        &mut hash,
+    );
+    for factor in ac {
+        let quantise = |value: f64| {
+            let value = value / maximum;
+            (value.signum() * value.abs().sqrt() * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        };
+        base83(
+            quantise(factor[0]) * 19 * 19 + quantise(factor[1]) * 19 + quantise(factor[2]),
+            2,
+            &mut hash,
+        );
+    }
+    hash
+}
+
+/// Wraps a resolver and fills `Picture::placeholder` from the image file
+/// below `local.base_dir`. Remote images and images that can't be read or
+/// decoded get no placeholder.
+pub struct PlaceholderResolver<R> {
+    pub inner: R,
+    pub local: LocalImageResolver,
+    pub config: PlaceholderConfig,
+}
+
+impl<R: AssetReferenceResolver> PlaceholderResolver<R> {
+    pub fn new(inner: R, base_dir: impl Into<PathBuf>, config: PlaceholderConfig) -> Self {
+        Self {
+            inner,
+            local: LocalImageResolver::new(base_dir),
+            config,
+        }
+    }
+}
+
+impl<R: AssetReferenceResolver> AssetReferenceResolver for PlaceholderResolver<R> {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        self.inner.resolve(context)
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        let mut picture = self.inner.resolve_picture(context)?;
+        // remote images and files that can't be read keep their picture
+        let bytes = self
+            .local
+            .path(context.reference)
+            .ok()
+            .and_then(|path| fs::read(path).ok());
+        if let Some(bytes) = bytes {
+            picture.placeholder = placeholder(&bytes, &self.config);
+        }
+        Ok(picture)
+    }
+
+    fn resolve_asset(&self, context: &ImageContext) -> Result<Option<Asset>, ResolveError> {
+        self.inner.resolve_asset(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, MarkDef, Options};
+    use image::Rgb;
+    use pulldown_cmark::Parser;
+    use std::env;
+
+    fn png(image: RgbImage) -> Vec<u8> {
+        let mut png = Cursor::new(vec![]);
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!("", base64(b""));
+        assert_eq!("Zg==", base64(b"f"));
+        assert_eq!("Zm8=", base64(b"fo"));
+        assert_eq!("Zm9vYmFy", base64(b"foobar"));
+    }
+
+    #[test]
+    fn computes_placeholders() {
+        let red = png(RgbImage::from_pixel(64, 48, Rgb([255, 0, 0])));
+
+        let computed = placeholder(&red, &PlaceholderConfig::default()).unwrap();
+
+        assert_eq!(
+            Some("LDTI:j]9fQ]9|co1fQo1fQfQfQfQ".to_owned()),
+            computed.blurhash
+        );
+        assert_eq!(Some("#ff0000".to_owned()), computed.dominant_color);
+        let lqip = computed.lqip.unwrap();
+        assert!(lqip.starts_with("data:image/png;base64,iVBORw0KGgo"));
+        assert!(placeholder(
+            b"<svg width=\"1\" height=\"1\"/>",
+            &PlaceholderConfig::default()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn picks_the_most_common_color() {
+        let mut image = RgbImage::from_pixel(10, 10, Rgb([0, 0, 255]));
+        for x in 0..10 {
+            for y in 0..4 {
+                image.put_pixel(x, y, Rgb([250, 250, 250]));
+            }
+        }
+
+        assert_eq!("#0000ff", dominant_color(&image));
+    }
+
+    #[test]
+    fn wraps_resolvers() {
+        let dir = env::temp_dir().join(format!(
+            "pulldown_cmark_portabletext-placeholder-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("rock.png"),
+            png(RgbImage::from_pixel(64, 48, Rgb([160, 82, 45]))),
+        )
+        .unwrap();
+
+        let resolver = PlaceholderResolver::new(
+            LocalImageResolver::new(&dir),
+            &dir,
+            PlaceholderConfig {
+                thumbnail_size: None,
+                blurhash_components: None,
+                dominant_color: true,
+            },
+        );
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new("![A rock](rock.png)"),
+            Options {
+                asset_resolver: &resolver,
+                ..Options::default()
+            },
+        );
+
+        match &portabletext_output[0].mark_defs[0] {
+            MarkDef::Image(image) => assert_eq!(
+                Some(Placeholder {
+                    lqip: None,
+                    blurhash: None,
+                    dominant_color: Some("#a0522d".to_owned()),
+                }),
+                image.picture.placeholder
+            ),
+            mark_def => panic!("{:?}", mark_def),
+        }
+    }
+
+    #[test]
+    fn leaves_remote_images_without_placeholder() {
+        struct Remote;
+        impl AssetReferenceResolver for Remote {
+            fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+                Ok(context.reference.to_owned())
+            }
+            fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+                Ok(Picture {
+                    src: context.reference.to_owned(),
+                    alt: context.alt.to_owned(),
+                    width: 640,
+                    height: 480,
+                    sources: vec![],
+                    placeholder: None,
+                })
+            }
+        }
+
+        let resolver =
+            PlaceholderResolver::new(Remote, env::temp_dir(), PlaceholderConfig::default());
+        let context = ImageContext {
+            reference: "https://example.com/rock.png",
+            alt: "",
+            title: None,
+            block: None,
+            source_position: None,
+        };
+
+        let picture = resolver.resolve_picture(&context).unwrap();
+        assert_eq!(None, picture.placeholder);
+    }
+}
@@ -0,0 +1,140 @@
+use super::BlockNode;
+
+const ELLIPSIS: char = '…';
+
+/// Concatenates the text of `blocks`. Blocks are separated by a blank line,
+/// consecutive list items by a single newline. Code blocks contribute their
+/// code and container objects the text of their content, inline objects such
+/// as figures are skipped.
+pub fn to_plain_text(blocks: &[BlockNode]) -> String {
+    let mut text = String::new();
+    let mut previous: Option<&BlockNode> = None;
+
+    for block in blocks {
+        let block_text = block_text(block);
+        if block_text.is_empty() {
+            continue;
+        }
+
+        if let Some(previous) = previous {
+            if previous.list_item.is_some() && block.list_item.is_some() {
+                text.push('\n');
+            } else {
+                text.push_str("\n\n");
+            }
+        }
+        text.push_str(&block_text);
+        previous = Some(block);
+    }
+
+    text
+}
+
+/// Like `to_plain_text`, but truncated to at most `max_chars` characters.
+pub fn to_plain_text_truncated(blocks: &[BlockNode], max_chars: usize) -> String {
+    truncate(&to_plain_text(blocks), max_chars)
+}
+
+/// Truncates `text` to at most `max_chars` characters, cutting at the last
+/// word boundary and appending an ellipsis when anything was cut.
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_owned();
+    }
+    if max_chars == 0 {
+        return String::new();
+    }
+
+    // keep room for the ellipsis
+    let limit = max_chars - 1;
+    let cut = text
+        .char_indices()
+        .nth(limit)
+        .map(|(index, _)| index)
+        .unwrap_or_else(|| text.len());
+    let head = &text[..cut];
+
+    let at_boundary = text[cut..].starts_with(char::is_whitespace);
+    let head = if at_boundary {
+        head
+    } else {
+        match head.rfind(char::is_whitespace) {
+            Some(index) => &head[..index],
+            // a single word longer than the limit, cut it mid-word
+            None => head,
+        }
+    };
+
+    let mut truncated = head
+        .trim_end_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation())
+        .to_owned();
+    truncated.push(ELLIPSIS);
+    truncated
+}
+
+pub(crate) fn block_text(block: &BlockNode) -> String {
+    if let Some(code) = &block.code {
+        return code.trim_end_matches('\n').to_owned();
+    }
+    if !block.content.is_empty() {
+        return to_plain_text(&block.content);
+    }
+
+    let text: String = block
+        .children
+        .iter()
+        .filter(|span| span._type == "span")
+        .map(|span| span.text.as_str())
+        .collect();
+    text.trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext;
+    use pulldown_cmark::Parser;
+
+    fn convert(markdown_input: &str) -> Vec<BlockNode> {
+        let parser = Parser::new(markdown_input);
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext(&mut portabletext_output, parser);
+        portabletext_output
+    }
+
+    #[test]
+    fn separates_blocks_and_list_items() {
+        let blocks = convert(
+            "# Title\n\nSome *emphasised* [text](https://example.com).\n\n* one\n* two\n\n```rust\nfn main() {}\n```",
+        );
+
+        assert_eq!(
+            "Title\n\nSome emphasised text.\n\none\ntwo\n\nfn main() {}",
+            to_plain_text(&blocks)
+        );
+    }
+
+    #[test]
+    fn skips_figures() {
+        let blocks = convert("Look: ![a rock](/rock.jpg)");
+
+        assert_eq!("Look:", to_plain_text(&blocks));
+    }
+
+    #[test]
+    fn truncates_at_word_boundaries() {
+        assert_eq!("short", truncate("short", 10));
+        assert_eq!("Hello…", truncate("Hello, wonderful world", 12));
+        assert_eq!("Hello wonderful…", truncate("Hello wonderful world", 17));
+        assert_eq!("Hello…", truncate("Hello wonderful world", 15));
+        assert_eq!("abcd…", truncate("abcdefghij", 5));
+        assert_eq!("", truncate("abc", 0));
+    }
+
+    #[test]
+    fn truncates_blocks() {
+        let blocks = convert("First paragraph here.\n\nSecond one.");
+
+        assert_eq!("First paragraph…", to_plain_text_truncated(&blocks, 20));
+    }
+}
@@ -0,0 +1,348 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use super::{Asset, AssetReferenceResolver, ImageContext, Picture, ResolveError};
+
+impl<R: AssetReferenceResolver + ?Sized> AssetReferenceResolver for &R {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        (**self).resolve(context)
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        (**self).resolve_picture(context)
+    }
+
+    fn resolve_asset(&self, context: &ImageContext) -> Result<Option<Asset>, ResolveError> {
+        (**self).resolve_asset(context)
+    }
+}
+
+impl<R: AssetReferenceResolver + ?Sized> AssetReferenceResolver for Box<R> {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        (**self).resolve(context)
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        (**self).resolve_picture(context)
+    }
+
+    fn resolve_asset(&self, context: &ImageContext) -> Result<Option<Asset>, ResolveError> {
+        (**self).resolve_asset(context)
+    }
+}
+
+/// Keeps remote and other non-local references as they are. The dimensions
+/// are unknown and left at 0.
+pub struct PassthroughResolver;
+
+impl AssetReferenceResolver for PassthroughResolver {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        Ok(context.reference.to_owned())
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        Ok(Picture {
+            src: context.reference.to_owned(),
+            alt: context.alt.to_owned(),
+            width: 0,
+            height: 0,
+            sources: vec![],
+            placeholder: None,
+        })
+    }
+}
+
+/// Remembers the results, including failures, of the wrapped resolver per
+/// reference, so it only sees each image once. Assumes the results only
+/// depend on the reference; the alt text is always taken from the context.
+pub struct CachingResolver<R> {
+    pub inner: R,
+    resolved: RefCell<HashMap<String, Result<String, ResolveError>>>,
+    pictures: RefCell<HashMap<String, Result<Picture, ResolveError>>>,
+    assets: RefCell<HashMap<String, Result<Option<Asset>, ResolveError>>>,
+}
+
+impl<R: AssetReferenceResolver> CachingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            resolved: RefCell::new(HashMap::new()),
+            pictures: RefCell::new(HashMap::new()),
+            assets: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Forgets everything resolved so far.
+    pub fn clear(&self) {
+        self.resolved.borrow_mut().clear();
+        self.pictures.borrow_mut().clear();
+        self.assets.borrow_mut().clear();
+    }
+}
+
+fn cached<T: Clone>(
+    cache: &RefCell<HashMap<String, Result<T, ResolveError>>>,
+    reference: &str,
+    resolve: impl FnOnce() -> Result<T, ResolveError>,
+) -> Result<T, ResolveError> {
+    if let Some(result) = cache.borrow().get(reference) {
+        return result.clone();
+    }
+    let result = resolve();
+    cache
+        .borrow_mut()
+        .insert(reference.to_owned(), result.clone());
+    result
+}
+
+impl<R: AssetReferenceResolver> AssetReferenceResolver for CachingResolver<R> {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        cached(&self.resolved, context.reference, || {
+            self.inner.resolve(context)
+        })
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        let mut picture = cached(&self.pictures, context.reference, || {
+            self.inner.resolve_picture(context)
+        })?;
+        picture.alt = context.alt.to_owned();
+        Ok(picture)
+    }
+
+    fn resolve_asset(&self, context: &ImageContext) -> Result<Option<Asset>, ResolveError> {
+        cached(&self.assets, context.reference, || {
+            self.inner.resolve_asset(context)
+        })
+    }
+}
+
+/// Tries resolvers in order, for each call separately: the picture comes
+/// from the first resolver whose `resolve_picture` succeeds, so a local
+/// resolver missing the file falls back to the next one.
+pub struct FallbackResolver<'a> {
+    pub resolvers: Vec<Box<dyn AssetReferenceResolver + 'a>>,
+}
+
+impl<'a> FallbackResolver<'a> {
+    pub fn new() -> Self {
+        Self { resolvers: vec![] }
+    }
+
+    pub fn or(mut self, resolver: impl AssetReferenceResolver + 'a) -> Self {
+        self.resolvers.push(Box::new(resolver));
+        self
+    }
+
+    fn first<T>(
+        &self,
+        context: &ImageContext,
+        call: impl Fn(&dyn AssetReferenceResolver) -> Result<T, ResolveError>,
+    ) -> Result<T, ResolveError> {
+        let mut messages = vec![];
+        for resolver in &self.resolvers {
+            match call(resolver.as_ref()) {
+                Ok(result) => return Ok(result),
+                Err(err) => messages.push(err.message),
+            }
+        }
+        if messages.is_empty() {
+            messages.push("no resolvers".to_owned());
+        }
+        Err(ResolveError::new(context.reference, messages.join("; ")))
+    }
+}
+
+impl<'a> Default for FallbackResolver<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> AssetReferenceResolver for FallbackResolver<'a> {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        self.first(context, |resolver| resolver.resolve(context))
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        self.first(context, |resolver| resolver.resolve_picture(context))
+    }
+
+    fn resolve_asset(&self, context: &ImageContext) -> Result<Option<Asset>, ResolveError> {
+        self.first(context, |resolver| resolver.resolve_asset(context))
+    }
+}
+
+/// Sends each reference to the resolver registered for the longest prefix it
+/// starts with, e.g. `/assets/` to a `LocalImageResolver` and `https://` to
+/// the `PassthroughResolver`.
+pub struct PrefixResolver<'a> {
+    pub routes: Vec<(String, Box<dyn AssetReferenceResolver + 'a>)>,
+}
+
+impl<'a> PrefixResolver<'a> {
+    pub fn new() -> Self {
+        Self { routes: vec![] }
+    }
+
+    pub fn route(
+        mut self,
+        prefix: impl Into<String>,
+        resolver: impl AssetReferenceResolver + 'a,
+    ) -> Self {
+        self.routes.push((prefix.into(), Box::new(resolver)));
+        self
+    }
+
+    fn pick(&self, context: &ImageContext) -> Result<&dyn AssetReferenceResolver, ResolveError> {
+        self.routes
+            .iter()
+            .filter(|(prefix, _)| context.reference.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, resolver)| resolver.as_ref())
+            .ok_or_else(|| ResolveError::new(context.reference, "no resolver for this prefix"))
+    }
+}
+
+impl<'a> Default for PrefixResolver<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> AssetReferenceResolver for PrefixResolver<'a> {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        self.pick(context)?.resolve(context)
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        self.pick(context)?.resolve_picture(context)
+    }
+
+    fn resolve_asset(&self, context: &ImageContext) -> Result<Option<Asset>, ResolveError> {
+        self.pick(context)?.resolve_asset(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::local::LocalImageResolver;
+    use std::cell::Cell;
+
+    fn context(reference: &str) -> ImageContext<'_> {
+        ImageContext {
+            reference,
+            alt: "",
+            title: None,
+            block: None,
+            source_position: None,
+        }
+    }
+
+    /// Resolves references with a suffix and counts its calls.
+    struct Suffix {
+        suffix: &'static str,
+        calls: Cell<usize>,
+    }
+
+    impl Suffix {
+        fn new(suffix: &'static str) -> Self {
+            Self {
+                suffix,
+                calls: Cell::new(0),
+            }
+        }
+    }
+
+    impl AssetReferenceResolver for Suffix {
+        fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+            self.calls.set(self.calls.get() + 1);
+            if context.reference.ends_with(self.suffix) {
+                Ok(format!("{}:{}", self.suffix, context.reference))
+            } else {
+                Err(ResolveError::new(context.reference, self.suffix))
+            }
+        }
+
+        fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+            let mut picture = PassthroughResolver.resolve_picture(context)?;
+            picture.src = self.resolve(context)?;
+            Ok(picture)
+        }
+    }
+
+    #[test]
+    fn caches_results_and_failures() {
+        let inner = Suffix::new(".png");
+        let resolver = CachingResolver::new(&inner);
+
+        for _ in 0..3 {
+            assert!(resolver.resolve(&context("/rock.png")).is_ok());
+            assert!(resolver.resolve(&context("/rock.gif")).is_err());
+        }
+        let picture = resolver
+            .resolve_picture(&ImageContext {
+                alt: "A rock",
+                ..context("/rock.png")
+            })
+            .unwrap();
+
+        assert_eq!(3, inner.calls.get());
+        assert_eq!("A rock", picture.alt);
+        resolver.clear();
+        resolver.resolve(&context("/rock.png")).unwrap();
+        assert_eq!(4, inner.calls.get());
+    }
+
+    #[test]
+    fn falls_back_in_order() {
+        let resolver = FallbackResolver::new()
+            .or(Suffix::new(".png"))
+            .or(Suffix::new(".gif"));
+
+        assert_eq!(
+            ".gif:/rock.gif",
+            resolver.resolve_picture(&context("/rock.gif")).unwrap().src
+        );
+        assert_eq!(
+            ResolveError::new("/rock.jpg", ".png; .gif"),
+            resolver.resolve(&context("/rock.jpg")).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn falls_back_when_the_picture_fails() {
+        let fallback = Suffix::new(".png");
+        let resolver = FallbackResolver::new()
+            .or(LocalImageResolver::new("/nonexistent"))
+            .or(&fallback);
+
+        let picture = resolver.resolve_picture(&context("/rock.png")).unwrap();
+
+        assert_eq!(".png:/rock.png", picture.src);
+        assert_eq!(1, fallback.calls.get());
+    }
+
+    #[test]
+    fn routes_by_longest_prefix() {
+        let resolver = PrefixResolver::new()
+            .route("/", Suffix::new(".png"))
+            .route("/assets/", Suffix::new(".gif"))
+            .route("https://", PassthroughResolver);
+
+        assert_eq!(
+            Ok(".gif:/assets/rock.gif".to_owned()),
+            resolver.resolve(&context("/assets/rock.gif"))
+        );
+        assert_eq!(
+            Ok(".png:/rock.png".to_owned()),
+            resolver.resolve(&context("/rock.png"))
+        );
+        assert_eq!(
+            Ok("https://example.com/rock.jpg".to_owned()),
+            resolver.resolve(&context("https://example.com/rock.jpg"))
+        );
+        assert!(resolver.resolve(&context("rock.png")).is_err());
+    }
+}
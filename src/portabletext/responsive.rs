@@ -0,0 +1,237 @@
+use super::image_size::ImageFormat;
+use super::{AssetReferenceResolver, ImageContext, Picture, ResolveError, Source};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Breakpoint {
+    pub width: u32,
+    /// Media query for the `<source>`, e.g. `(max-width: 640px)`.
+    pub media: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResponsiveConfig {
+    pub breakpoints: Vec<Breakpoint>,
+    /// Most preferred format first, e.g. avif, webp, jpeg.
+    pub formats: Vec<ImageFormat>,
+    /// URL of a single rendition. `{src}`, `{width}`, `{height}` and
+    /// `{format}` (the file extension) are replaced. A `?` right after
+    /// `{src}` becomes `&` when the source URL has a query already.
+    pub url_template: String,
+    /// Also generate renditions wider than the original image.
+    pub upscale: bool,
+}
+
+impl Default for ResponsiveConfig {
+    fn default() -> Self {
+        Self {
+            breakpoints: vec![],
+            formats: vec![ImageFormat::Avif, ImageFormat::Webp, ImageFormat::Jpeg],
+            url_template: "{src}?w={width}&fm={format}".to_owned(),
+            upscale: false,
+        }
+    }
+}
+
+/// One `Source` per format and breakpoint, with the height scaled to the
+/// aspect ratio of the `width` x `height` original. Images narrower than
+/// every breakpoint get a single source per format at the smallest
+/// breakpoint, in their original size unless `upscale` is set. Without known
+/// dimensions (a zero width) no heights can be computed and no sources are
+/// returned.
+pub fn sources(src: &str, width: u32, height: u32, config: &ResponsiveConfig) -> Vec<Source> {
+    if width == 0 {
+        return vec![];
+    }
+
+    let mut renditions: Vec<(u32, &Breakpoint)> = config
+        .breakpoints
+        .iter()
+        .filter(|breakpoint| config.upscale || breakpoint.width <= width)
+        .map(|breakpoint| (breakpoint.width, breakpoint))
+        .collect();
+    if renditions.is_empty() {
+        if let Some(smallest) = config.breakpoints.iter().min_by_key(|b| b.width) {
+            renditions.push((smallest.width.min(width), smallest));
+        }
+    }
+
+    let template = if src.contains('?') {
+        config.url_template.replace("{src}?", "{src}&")
+    } else {
+        config.url_template.to_owned()
+    };
+
+    let mut sources = vec![];
+    for format in &config.formats {
+        for (rendition_width, breakpoint) in &renditions {
+            let scaled_height =
+                (f64::from(*rendition_width) * f64::from(height) / f64::from(width)).round() as u32;
+            let srcset = template
+                .replace("{src}", src)
+                .replace("{width}", &rendition_width.to_string())
+                .replace("{height}", &scaled_height.to_string())
+                .replace("{format}", format.extension());
+
+            sources.push(Source {
+                srcset,
+                width: *rendition_width,
+                height: scaled_height,
+                attr_type: format.mime_type().to_owned(),
+                media: breakpoint.media.clone(),
+            });
+        }
+    }
+    sources
+}
+
+/// Wraps a resolver and fills `Picture::sources` from a `ResponsiveConfig`.
+pub struct ResponsiveResolver<R> {
+    pub inner: R,
+    pub config: ResponsiveConfig,
+}
+
+impl<R: AssetReferenceResolver> ResponsiveResolver<R> {
+    pub fn new(inner: R, config: ResponsiveConfig) -> Self {
+        Self { inner, config }
+    }
+}
+
+impl<R: AssetReferenceResolver> AssetReferenceResolver for ResponsiveResolver<R> {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        self.inner.resolve(context)
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        let mut picture = self.inner.resolve_picture(context)?;
+        picture.sources = sources(&picture.src, picture.width, picture.height, &self.config);
+        Ok(picture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ResponsiveConfig {
+        ResponsiveConfig {
+            breakpoints: vec![
+                Breakpoint {
+                    width: 640,
+                    media: Some("(max-width: 640px)".to_owned()),
+                },
+                Breakpoint {
+                    width: 1280,
+                    media: None,
+                },
+                Breakpoint {
+                    width: 2560,
+                    media: None,
+                },
+            ],
+            formats: vec![ImageFormat::Webp, ImageFormat::Jpeg],
+            url_template: "https://cdn.example.com{src}?w={width}&h={height}&fm={format}"
+                .to_owned(),
+            upscale: false,
+        }
+    }
+
+    #[test]
+    fn generates_sources_per_format_and_breakpoint() {
+        let sources = sources("/rock.jpg", 2000, 1500, &config());
+
+        assert_eq!(4, sources.len());
+        assert_eq!(
+            Source {
+                srcset: "https://cdn.example.com/rock.jpg?w=640&h=480&fm=webp".to_owned(),
+                width: 640,
+                height: 480,
+                attr_type: "image/webp".to_owned(),
+                media: Some("(max-width: 640px)".to_owned()),
+            },
+            sources[0]
+        );
+        assert_eq!(
+            ("image/jpeg", 1280, 960),
+            (
+                sources[3].attr_type.as_str(),
+                sources[3].width,
+                sources[3].height
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_tiny_images_at_the_smallest_breakpoint_without_upscaling() {
+        let mut config = config();
+        config.breakpoints.reverse();
+        let tiny = sources("/icon.png", 100, 50, &config);
+
+        assert_eq!(2, tiny.len());
+        assert_eq!((100, 50), (tiny[0].width, tiny[0].height));
+        assert_eq!(Some("(max-width: 640px)".to_owned()), tiny[0].media);
+
+        config.upscale = true;
+        let upscaled = sources("/icon.png", 100, 50, &config);
+        assert_eq!((2560, 1280), (upscaled[0].width, upscaled[0].height));
+    }
+
+    #[test]
+    fn appends_to_existing_queries() {
+        let config = ResponsiveConfig {
+            breakpoints: vec![Breakpoint {
+                width: 640,
+                media: None,
+            }],
+            formats: vec![ImageFormat::Webp],
+            ..ResponsiveConfig::default()
+        };
+
+        assert_eq!(
+            "/rock.jpg?v=2&w=640&fm=webp",
+            sources("/rock.jpg?v=2", 2000, 1500, &config)[0].srcset
+        );
+        assert_eq!(
+            "/rock.jpg?w=640&fm=webp",
+            sources("/rock.jpg", 2000, 1500, &config)[0].srcset
+        );
+    }
+
+    #[test]
+    fn needs_dimensions() {
+        assert!(sources("/rock.jpg", 0, 0, &config()).is_empty());
+    }
+
+    #[test]
+    fn wraps_resolvers() {
+        struct Fixed;
+        impl AssetReferenceResolver for Fixed {
+            fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+                Ok(context.reference.to_owned())
+            }
+            fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+                Ok(Picture {
+                    src: context.reference.to_owned(),
+                    alt: context.alt.to_owned(),
+                    width: 1280,
+                    height: 720,
+                    sources: vec![],
+                    placeholder: None,
+                })
+            }
+        }
+
+        let resolver = ResponsiveResolver::new(Fixed, config());
+        let picture = resolver
+            .resolve_picture(&ImageContext {
+                reference: "/rock.jpg",
+                alt: "",
+                title: None,
+                block: None,
+                source_position: None,
+            })
+            .unwrap();
+
+        assert_eq!(4, picture.sources.len());
+        assert_eq!(720, picture.sources[1].height);
+    }
+}
@@ -0,0 +1,286 @@
+use std::error::Error;
+use std::fmt;
+
+use serde_json::{json, Value};
+
+use super::diff::{diff, BlockChange};
+use super::{random_key, BlockNode};
+
+#[derive(Debug, PartialEq)]
+pub struct MissingKeyError {
+    /// Index of the old block without a `_key`.
+    pub index: usize,
+}
+
+impl fmt::Display for MissingKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {} has no _key and cannot be patched", self.index)
+    }
+}
+
+impl Error for MissingKeyError {}
+
+/// Builds the Sanity mutations that turn the array `field` of document
+/// `document_id` from `old` into `new`.
+///
+/// Every block in `old` needs a `_key`, as stored in the CMS; convert with
+/// `Options::keys` to write blocks with keys. Blocks in `new`
+/// that are matched to an old block take over its key, inserted blocks keep
+/// their own key or get a random one. The mutations are meant to be sent in
+/// order as a single transaction:
+///
+/// 1. `unset` for removed and moved blocks,
+/// 2. `set` for blocks modified in place,
+/// 3. `insert` for new and moved blocks, each after its predecessor.
+pub fn mutations(
+    document_id: &str,
+    field: &str,
+    old: &[BlockNode],
+    new: &[BlockNode],
+) -> Result<Vec<Value>, MissingKeyError> {
+    let old_keys = old
+        .iter()
+        .enumerate()
+        .map(|(index, block)| block._key.clone().ok_or(MissingKeyError { index }))
+        .collect::<Result<Vec<String>, _>>()?;
+
+    let changes = diff(old, new);
+    let path = |key: &str| format!("{}[_key==\"{}\"]", field, key);
+    let patch = |operation: Value| {
+        let mut patch = json!({ "id": document_id });
+        merge(&mut patch, operation);
+        json!({ "patch": patch })
+    };
+
+    let mut new_keys: Vec<Option<String>> = vec![None; new.len()];
+    let mut moved = vec![false; new.len()];
+    let mut removals = vec![];
+    let mut sets = vec![];
+
+    for change in &changes {
+        match change {
+            BlockChange::Removed { index, .. } => removals.push(old_keys[*index].to_owned()),
+            BlockChange::Moved {
+                old_index,
+                new_index,
+            } => {
+                moved[*new_index] = true;
+                removals.push(old_keys[*old_index].to_owned());
+            }
+            BlockChange::Modified(modification) => sets.push(modification.new_index),
+            BlockChange::Inserted { .. } => {}
+        }
+    }
+    // matched blocks without changes don't show up in the diff, take their
+    // keys from the modifications or from the unchanged old block
+    for (new_index, old_index) in matched(&changes, old.len(), new.len()) {
+        new_keys[new_index] = Some(old_keys[old_index].to_owned());
+    }
+
+    let keyed = |index: usize, key: &str| {
+        let mut block = new[index].clone();
+        block._key = Some(key.to_owned());
+        serde_json::to_value(block).unwrap()
+    };
+
+    let mut result = vec![];
+    if old.is_empty() && !new.is_empty() {
+        result.push(json!({ "patch": { "id": document_id, "setIfMissing": { field: [] } } }));
+    }
+    for key in removals {
+        result.push(patch(json!({ "unset": [path(&key)] })));
+    }
+    for index in sets.into_iter().filter(|&index| !moved[index]) {
+        let key = new_keys[index].as_ref().unwrap();
+        result.push(patch(json!({ "set": { path(key): keyed(index, key) } })));
+    }
+
+    let mut previous: Option<String> = None;
+    let mut kept_any = new_keys
+        .iter()
+        .zip(&moved)
+        .any(|(key, moved)| key.is_some() && !moved);
+    for index in 0..new.len() {
+        let needs_insert = new_keys[index].is_none() || moved[index];
+        let key = match &new_keys[index] {
+            Some(key) => key.to_owned(),
+            None => new[index]._key.clone().unwrap_or_else(random_key),
+        };
+
+        if needs_insert {
+            let mut insert = match &previous {
+                Some(previous) => json!({ "after": path(previous) }),
+                None if kept_any => json!({ "before": format!("{}[0]", field) }),
+                None => json!({ "after": format!("{}[-1]", field) }),
+            };
+            merge(&mut insert, json!({ "items": [keyed(index, &key)] }));
+            result.push(patch(json!({ "insert": insert })));
+            kept_any = true;
+        }
+        previous = Some(key);
+    }
+
+    Ok(result)
+}
+
+/// `(new_index, old_index)` for every block that exists in both documents.
+fn matched(changes: &[BlockChange], old_len: usize, new_len: usize) -> Vec<(usize, usize)> {
+    let mut removed = vec![false; old_len];
+    let mut inserted = vec![false; new_len];
+    let mut pairs = vec![];
+
+    for change in changes {
+        match change {
+            BlockChange::Removed { index, .. } => removed[*index] = true,
+            BlockChange::Inserted { index, .. } => inserted[*index] = true,
+            BlockChange::Moved {
+                old_index,
+                new_index,
+            } => pairs.push((*new_index, *old_index)),
+            BlockChange::Modified(modification) => {
+                pairs.push((modification.new_index, modification.old_index))
+            }
+        }
+    }
+
+    // unchanged, unmoved blocks keep their relative order, so they can be
+    // zipped up in order
+    let paired_old: Vec<usize> = pairs.iter().map(|&(_, o)| o).collect();
+    let paired_new: Vec<usize> = pairs.iter().map(|&(n, _)| n).collect();
+    let old_rest = (0..old_len).filter(|o| !removed[*o] && !paired_old.contains(o));
+    let new_rest = (0..new_len).filter(|n| !inserted[*n] && !paired_new.contains(n));
+    pairs.extend(new_rest.zip(old_rest));
+
+    pairs.sort_unstable();
+    pairs.dedup();
+    pairs
+}
+
+fn merge(target: &mut Value, other: Value) {
+    if let (Some(target), Value::Object(other)) = (target.as_object_mut(), other) {
+        target.extend(other);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::SpanNode;
+
+    fn block(key: &str, text: &str) -> BlockNode {
+        BlockNode::default("normal".to_owned())
+            .with_key(key.to_owned())
+            .with_children(vec![SpanNode::span(text.to_owned(), vec![])])
+    }
+
+    fn unkeyed(text: &str) -> BlockNode {
+        let mut block = block("", text);
+        block._key = None;
+        block
+    }
+
+    #[test]
+    fn requires_keys_on_old_blocks() {
+        assert_eq!(
+            Err(MissingKeyError { index: 0 }),
+            mutations("doc", "body", &[unkeyed("a")], &[])
+        );
+    }
+
+    #[test]
+    fn unchanged_documents_need_no_mutations() {
+        let old = vec![block("a", "First block"), block("b", "Second block")];
+        let new = vec![unkeyed("First block"), unkeyed("Second block")];
+
+        assert_eq!(
+            Vec::<Value>::new(),
+            mutations("doc", "body", &old, &new).unwrap()
+        );
+    }
+
+    #[test]
+    fn patches_by_key() {
+        let old = vec![
+            block("a", "First block"),
+            block("b", "Second block"),
+            block("c", "Third block"),
+        ];
+        let new = vec![
+            unkeyed("First block changed"),
+            block("new", "A new block"),
+            unkeyed("Third block"),
+        ];
+
+        let mutations = mutations("doc", "body", &old, &new).unwrap();
+
+        assert_eq!(
+            vec![
+                json!({ "patch": { "id": "doc", "unset": ["body[_key==\"b\"]"] } }),
+                json!({ "patch": { "id": "doc", "set": {
+                    "body[_key==\"a\"]": serde_json::to_value(block("a", "First block changed")).unwrap()
+                } } }),
+                json!({ "patch": { "id": "doc", "insert": {
+                    "after": "body[_key==\"a\"]",
+                    "items": [serde_json::to_value(block("new", "A new block")).unwrap()]
+                } } }),
+            ],
+            mutations
+        );
+    }
+
+    #[test]
+    fn moves_are_unset_and_inserted() {
+        let old = vec![block("a", "First block"), block("b", "Second block")];
+        let new = vec![unkeyed("Second block"), unkeyed("First block")];
+
+        let mutations = mutations("doc", "body", &old, &new).unwrap();
+
+        assert_eq!(2, mutations.len());
+        assert_eq!(
+            json!({ "patch": { "id": "doc", "unset": ["body[_key==\"b\"]"] } }),
+            mutations[0]
+        );
+        assert_eq!(json!("body[0]"), mutations[1]["patch"]["insert"]["before"]);
+        assert_eq!(
+            json!("b"),
+            mutations[1]["patch"]["insert"]["items"][0]["_key"]
+        );
+    }
+
+    #[test]
+    fn patches_converted_blocks_with_keys() {
+        let convert = |markdown_input: &str| {
+            let mut portabletext_output = vec![];
+            crate::portabletext::push_portabletext_with_opts(
+                &mut portabletext_output,
+                pulldown_cmark::Parser::new(markdown_input),
+                crate::portabletext::Options {
+                    callouts: true,
+                    keys: true,
+                    ..crate::portabletext::Options::default()
+                },
+            );
+            portabletext_output
+        };
+        let old = convert("First block\n\n> [!NOTE]\n> Inside");
+        let new = convert("First block changed\n\n> [!NOTE]\n> Inside");
+
+        assert!(old[1]._key.is_some());
+        assert!(old[1].content[0]._key.is_some());
+        let mutations = mutations("doc", "body", &old, &new).unwrap();
+        assert_eq!(1, mutations.len());
+        let path = format!("body[_key==\"{}\"]", old[0]._key.as_ref().unwrap());
+        assert!(mutations[0]["patch"]["set"].get(&path).is_some());
+    }
+
+    #[test]
+    fn fills_empty_documents() {
+        let mutations = mutations("doc", "body", &[], &[block("a", "Hello")]).unwrap();
+
+        assert_eq!(
+            json!({ "patch": { "id": "doc", "setIfMissing": { "body": [] } } }),
+            mutations[0]
+        );
+        assert_eq!(json!("body[-1]"), mutations[1]["patch"]["insert"]["after"]);
+    }
+}
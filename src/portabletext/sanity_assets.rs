@@ -0,0 +1,191 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+#[cfg(feature = "serde_serialization")]
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+use super::image_size::image_size;
+use super::local::LocalImageResolver;
+use super::{Asset, AssetReferenceResolver, ImageContext, Picture, ResolveError};
+
+/// A local image that has to be uploaded for its `_ref` to exist in Sanity.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde_serialization", derive(Serialize))]
+#[cfg_attr(feature = "serde_serialization", serde(rename_all = "camelCase"))]
+pub struct AssetUpload {
+    /// The asset document id, `image-<sha1>-<width>x<height>-<extension>`.
+    pub id: String,
+    pub path: PathBuf,
+    pub sha1: String,
+    pub width: u32,
+    pub height: u32,
+    pub extension: String,
+}
+
+/// Resolves local images (see `LocalImageResolver`) to Sanity image asset
+/// references, which are derived from the file content the same way Sanity
+/// names uploaded images. Every resolved image is recorded in `manifest`.
+pub struct SanityAssetResolver {
+    pub local: LocalImageResolver,
+    by_path: RefCell<HashMap<PathBuf, AssetUpload>>,
+    uploads: RefCell<Vec<AssetUpload>>,
+}
+
+impl SanityAssetResolver {
+    pub fn new(base_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            local: LocalImageResolver::new(base_dir),
+            by_path: RefCell::new(HashMap::new()),
+            uploads: RefCell::new(vec![]),
+        }
+    }
+
+    /// Every distinct asset resolved so far, in order of first use.
+    pub fn manifest(&self) -> Vec<AssetUpload> {
+        self.uploads.borrow().clone()
+    }
+
+    pub fn upload(&self, reference: &str) -> Result<AssetUpload, ResolveError> {
+        let path = self.local.path(reference)?;
+        if let Some(upload) = self.by_path.borrow().get(&path) {
+            return Ok(upload.clone());
+        }
+
+        let bytes = fs::read(&path)
+            .map_err(|e| ResolveError::new(reference, format!("{}: {}", path.display(), e)))?;
+        let size = image_size(&bytes)
+            .ok_or_else(|| ResolveError::new(reference, "unknown image format"))?;
+        let sha1 = format!("{:x}", Sha1::digest(&bytes));
+        let extension = size.format.extension().to_owned();
+
+        let upload = AssetUpload {
+            id: format!(
+                "image-{}-{}x{}-{}",
+                sha1, size.width, size.height, extension
+            ),
+            path: path.to_owned(),
+            sha1,
+            width: size.width,
+            height: size.height,
+            extension,
+        };
+
+        self.by_path.borrow_mut().insert(path, upload.clone());
+        let mut uploads = self.uploads.borrow_mut();
+        // identical files below different paths are the same asset
+        if !uploads.iter().any(|u| u.id == upload.id) {
+            uploads.push(upload.clone());
+        }
+        Ok(upload)
+    }
+}
+
+impl AssetReferenceResolver for SanityAssetResolver {
+    fn resolve(&self, context: &ImageContext) -> Result<String, ResolveError> {
+        self.local.resolve(context)
+    }
+
+    fn resolve_picture(&self, context: &ImageContext) -> Result<Picture, ResolveError> {
+        let upload = self.upload(context.reference)?;
+        Ok(Picture {
+            src: context.reference.to_owned(),
+            alt: context.alt.to_owned(),
+            width: upload.width,
+            height: upload.height,
+            sources: vec![],
+            placeholder: None,
+        })
+    }
+
+    fn resolve_asset(&self, context: &ImageContext) -> Result<Option<Asset>, ResolveError> {
+        let upload = self.upload(context.reference)?;
+        Ok(Some(Asset {
+            _type: "reference".to_owned(),
+            _ref: upload.id,
+            src: context.reference.to_owned(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portabletext::{self, MarkDef, Options};
+    use pulldown_cmark::Parser;
+    use std::env;
+
+    const GIF: &[u8] = b"GIF89a\x80\x02\xe0\x01";
+
+    fn base_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "pulldown_cmark_portabletext-sanity-assets-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("rock.gif"), GIF).unwrap();
+        fs::write(dir.join("copy.gif"), GIF).unwrap();
+        dir
+    }
+
+    #[test]
+    fn produces_sanity_asset_ids() {
+        let resolver = SanityAssetResolver::new(base_dir());
+
+        let upload = resolver.upload("/rock.gif").unwrap();
+
+        assert_eq!(
+            "image-b495ca6c5f9e8275d7b3692bd9050c6e2868110e-640x480-gif",
+            upload.id
+        );
+        assert_eq!((640, 480), (upload.width, upload.height));
+    }
+
+    #[test]
+    fn references_assets_and_lists_uploads() {
+        let resolver = SanityAssetResolver::new(base_dir());
+        let markdown_input = "![One](/rock.gif) ![Two](/rock.gif) ![Three](/copy.gif)";
+
+        let mut portabletext_output = vec![];
+        portabletext::push_portabletext_with_opts(
+            &mut portabletext_output,
+            Parser::new(markdown_input),
+            Options {
+                asset_resolver: &resolver,
+                ..Options::default()
+            },
+        );
+
+        let manifest = resolver.manifest();
+        assert_eq!(1, manifest.len());
+        match &portabletext_output[0].mark_defs[0] {
+            MarkDef::Image(image) => {
+                let asset = image.asset.as_ref().unwrap();
+                assert_eq!("reference", asset._type);
+                assert_eq!(manifest[0].id, asset._ref);
+            }
+            mark_def => panic!("{:?}", mark_def),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde_serialization")]
+    fn serializes_assets_as_references() {
+        let resolver = SanityAssetResolver::new(base_dir());
+        let context = ImageContext {
+            reference: "/rock.gif",
+            alt: "A rock",
+            title: None,
+            block: None,
+            source_position: None,
+        };
+
+        let asset = resolver.resolve_asset(&context).unwrap().unwrap();
+        assert_eq!(
+            serde_json::json!({ "_type": "reference", "_ref": asset._ref }),
+            serde_json::to_value(&asset).unwrap()
+        );
+    }
+}
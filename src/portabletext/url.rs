@@ -0,0 +1,215 @@
+//! Just enough URL handling for links in Markdown, without a full parser.
+
+/// HTML attributes holding a URL.
+pub(crate) const URL_ATTRIBUTES: &[&str] =
+    &["href", "src", "cite", "action", "formaction", "poster"];
+
+/// The lowercased scheme of an absolute URL, e.g. `https` or `mailto`.
+pub fn scheme(url: &str) -> Option<String> {
+    let (scheme, _) = url.trim_start().split_once(':')?;
+    let mut chars = scheme.chars();
+    let valid = chars.next()?.is_ascii_alphabetic()
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.');
+    if valid {
+        Some(scheme.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Whether `url` has neither a scheme nor starts with `//`.
+pub fn is_relative(url: &str) -> bool {
+    scheme(url).is_none() && !url.starts_with("//")
+}
+
+/// The lowercased host of an absolute or protocol relative URL, without
+/// userinfo and port.
+pub fn host(url: &str) -> Option<String> {
+    let rest = match scheme(url) {
+        Some(scheme) => &url.trim_start()[scheme.len() + 1..],
+        None => url,
+    };
+    let authority = rest.strip_prefix("//")?;
+    let authority = authority.split(['/', '?', '#']).next().unwrap_or(authority);
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    let host = match host.rfind(':') {
+        // keep IPv6 literals like [::1] intact
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+    };
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_ascii_lowercase())
+    }
+}
+
+/// Splits `url` before its query or fragment.
+pub fn split_suffix(url: &str) -> (&str, &str) {
+    match url.find(['?', '#']) {
+        Some(index) => url.split_at(index),
+        None => (url, ""),
+    }
+}
+
+/// Decodes `%XX` escapes. Malformed escapes and sequences that are not valid
+/// UTF-8 are kept as they are.
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escape = bytes
+            .get(index + 1..index + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escape {
+            Some(byte) if bytes[index] == b'%' => {
+                decoded.push(byte);
+                index += 3;
+            }
+            _ => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| text.to_owned())
+}
+
+/// Resolves `.` and `..` segments. Leading `..` of a relative path are kept.
+pub fn normalize_path(path: &str) -> String {
+    let absolute = path.starts_with('/');
+    let mut segments: Vec<&str> = vec![];
+    let mut parts = path.split('/').peekable();
+    while let Some(part) = parts.next() {
+        match part {
+            "" | "." if parts.peek().is_some() => {}
+            "." => segments.push(""),
+            ".." => {
+                if matches!(segments.last(), Some(last) if *last != "..") {
+                    segments.pop();
+                } else if !absolute {
+                    segments.push("..");
+                }
+                if parts.peek().is_none() {
+                    segments.push("");
+                }
+            }
+            _ => segments.push(part),
+        }
+    }
+
+    let joined = segments.join("/");
+    if absolute {
+        format!("/{}", joined)
+    } else {
+        joined
+    }
+}
+
+/// Resolves a relative `reference` against `base`, like a browser would.
+/// Absolute references are returned as they are.
+pub fn join(base: &str, reference: &str) -> String {
+    if !is_relative(reference) {
+        return reference.to_owned();
+    }
+    if reference.is_empty() {
+        return base.to_owned();
+    }
+
+    let (base_path, base_suffix) = split_suffix(base);
+    // scheme and authority, e.g. `https://example.com`
+    let origin_length = match base_path.find("//") {
+        Some(index) => {
+            let authority = index + 2;
+            authority
+                + base_path[authority..]
+                    .find('/')
+                    .unwrap_or(base_path.len() - authority)
+        }
+        None => 0,
+    };
+    let (origin, path) = base_path.split_at(origin_length);
+
+    if reference.starts_with('#') {
+        let query = base_suffix.split('#').next().unwrap_or("");
+        return format!("{}{}{}", base_path, query, reference);
+    }
+    if reference.starts_with('?') {
+        return format!("{}{}", base_path, reference);
+    }
+
+    let (reference_path, reference_suffix) = split_suffix(reference);
+    let path = if reference_path.starts_with('/') {
+        reference_path.to_owned()
+    } else {
+        let directory = &path[..path.rfind('/').map_or(0, |index| index + 1)];
+        let directory = if directory.is_empty() && !origin.is_empty() {
+            "/"
+        } else {
+            directory
+        };
+        format!("{}{}", directory, reference_path)
+    };
+    format!("{}{}{}", origin, normalize_path(&path), reference_suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_schemes_and_hosts() {
+        assert_eq!(Some("https".to_owned()), scheme("HTTPS://example.com"));
+        assert_eq!(
+            Some("javascript".to_owned()),
+            scheme(" javascript:alert(1)")
+        );
+        assert_eq!(None, scheme("/posts/a:b"));
+        assert_eq!(None, scheme("post.md"));
+        assert_eq!(
+            Some("example.com".to_owned()),
+            host("https://user@Example.com:8080/a?b")
+        );
+        assert_eq!(
+            Some("cdn.example.com".to_owned()),
+            host("//cdn.example.com")
+        );
+        assert_eq!(None, host("/posts/"));
+        assert_eq!(None, host("mailto:me@example.com"));
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!("my photo.png", percent_decode("my%20photo.png"));
+        assert_eq!("caf\u{e9}", percent_decode("caf%C3%A9"));
+        assert_eq!("100%", percent_decode("100%"));
+        assert_eq!("%zz%+1%2", percent_decode("%zz%+1%2"));
+        assert_eq!("%FF", percent_decode("%FF"));
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!("/b/c", normalize_path("/a/../b/./c"));
+        assert_eq!("/", normalize_path("/.."));
+        assert_eq!("../b/", normalize_path("a/../../b/."));
+        assert_eq!("a/", normalize_path("a/b/.."));
+    }
+
+    #[test]
+    fn joins_like_a_browser() {
+        let base = "https://example.com/blog/post/?page=2#top";
+
+        assert_eq!("https://example.com/blog/other/", join(base, "../other/"));
+        assert_eq!("https://example.com/blog/post/a.png", join(base, "a.png"));
+        assert_eq!("https://example.com/about", join(base, "/about"));
+        assert_eq!(
+            "https://example.com/blog/post/?page=2#end",
+            join(base, "#end")
+        );
+        assert_eq!("https://example.com/blog/post/?q", join(base, "?q"));
+        assert_eq!("https://example.com/a", join("https://example.com", "a"));
+        assert_eq!("mailto:me@example.com", join(base, "mailto:me@example.com"));
+    }
+}
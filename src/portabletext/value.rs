@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+
+#[cfg(feature = "serde_serialization")]
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+
+/// A JSON value, for the fields of custom annotations and objects.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    /// The value at `key`, if this is an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(object) => object.get(key),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Number(value as f64)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl From<BTreeMap<String, Value>> for Value {
+    fn from(object: BTreeMap<String, Value>) -> Self {
+        Value::Object(object)
+    }
+}
+
+#[cfg(feature = "fence_formats")]
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(value) => Value::Bool(value),
+            serde_json::Value::Number(number) => number.as_f64().map_or(Value::Null, Value::Number),
+            serde_json::Value::String(value) => Value::String(value),
+            serde_json::Value::Array(values) => Value::from(values),
+            serde_json::Value::Object(object) => Value::Object(
+                object
+                    .into_iter()
+                    .map(|(key, value)| (key, Value::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "serde_serialization")]
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Value::Null => serializer.serialize_unit(),
+            Value::Bool(value) => serializer.serialize_bool(*value),
+            // whole numbers are written without a fraction, like in JavaScript
+            Value::Number(value) if value.fract() == 0.0 && value.abs() < 9e15 => {
+                serializer.serialize_i64(*value as i64)
+            }
+            Value::Number(value) => serializer.serialize_f64(*value),
+            Value::String(value) => serializer.serialize_str(value),
+            Value::Array(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Value::Object(object) => {
+                let mut map = serializer.serialize_map(Some(object.len()))?;
+                for (key, value) in object {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_from_rust_values() {
+        let mut object = BTreeMap::new();
+        object.insert("title".to_owned(), Value::from("Rust"));
+        object.insert("year".to_owned(), Value::from(2015i64));
+        let value = Value::from(object);
+
+        assert_eq!(Some("Rust"), value.get("title").and_then(Value::as_str));
+        assert_eq!(Some(&Value::Number(2015.0)), value.get("year"));
+        assert_eq!(Value::Null, Value::from(None::<bool>));
+    }
+
+    #[test]
+    #[cfg(feature = "fence_formats")]
+    fn converts_from_serde_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(r#"{"a": [1, -2.5e1, true, null], "b": {"c": "q\"\u00e9"}}"#)
+                .unwrap();
+        let value = Value::from(json);
+
+        assert_eq!(
+            Some(&Value::from(vec![
+                Value::from(1i64),
+                Value::from(-25.0),
+                Value::from(true),
+                Value::Null
+            ])),
+            value.get("a")
+        );
+        assert_eq!(
+            Some("q\"\u{e9}"),
+            value
+                .get("b")
+                .and_then(|b| b.get("c"))
+                .and_then(Value::as_str)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde_serialization")]
+    fn serializes_as_json() {
+        let value = Value::from(vec![
+            Value::Null,
+            Value::from(true),
+            Value::from(1.5),
+            Value::from(3i64),
+            Value::from("a"),
+        ]);
+
+        assert_eq!(
+            r#"[null,true,1.5,3,"a"]"#,
+            serde_json::to_string(&value).unwrap()
+        );
+    }
+}